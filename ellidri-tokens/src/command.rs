@@ -105,6 +105,8 @@ commands! {
     Authenticate "AUTHENTICATE" 1
     Away     "AWAY"     0
     Cap      "CAP"      1
+    ChgHost  "CHGHOST"  3
+    Die      "DIE"      0
     Info     "INFO"     0
     Invite   "INVITE"   2
     Join     "JOIN"     1
@@ -125,12 +127,19 @@ commands! {
     PrivMsg  "PRIVMSG"  2
     Quit     "QUIT"     0
     Rehash   "REHASH"   0
+    Restart  "RESTART"  0
+    SaJoin   "SAJOIN"   2
+    SaMode   "SAMODE"   2
+    SaNick   "SANICK"   2
+    SaPart   "SAPART"   2
     SetName  "SETNAME"  1
+    Stats    "STATS"    1
     TagMsg   "TAGMSG"   1
     Time     "TIME"     0
     Topic    "TOPIC"    1
     User     "USER"     4
     Version  "VERSION"  0
+    WebIrc   "WEBIRC"   4
     Who      "WHO"      0
     WhoIs    "WHOIS"    1
 }
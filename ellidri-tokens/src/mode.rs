@@ -3,7 +3,7 @@
 use std::str;
 
 /// User modes supported by ellidri.  Advertised in welcome messages.
-pub const USER_MODES: &str = "aio";
+pub const USER_MODES: &str = "aiosZ";
 
 /// Channel modes that have no parameters and are supported by ellidri.  Advertised in welcome
 /// messages.
@@ -77,13 +77,14 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum UserChange {
     Invisible(bool),
     DeOperator,
+    ServerNotices(bool),
 }
 
 impl UserChange {
     /// Whether this change is enabling or disabling a mode.
     pub fn value(self) -> bool {
         match self {
-            Self::Invisible(v) => v,
+            Self::Invisible(v) | Self::ServerNotices(v) => v,
             Self::DeOperator => false,
         }
     }
@@ -93,6 +94,7 @@ impl UserChange {
         match self {
             Self::Invisible(_) => 'i',
             Self::DeOperator => 'o',
+            Self::ServerNotices(_) => 's',
         }
     }
 }
@@ -103,19 +105,21 @@ impl UserChange {
 ///
 /// ```rust
 /// # use ellidri_tokens::mode::{self, Error, UserChange};
-/// let mut query = mode::user_query("+io-oXa");
+/// let mut query = mode::user_query("+io-oXas");
 ///
 /// assert_eq!(query.next(), Some(Ok(UserChange::Invisible(true))));
 /// assert_eq!(query.next(), Some(Err(Error::Unchangeable('o', true))));
 /// assert_eq!(query.next(), Some(Ok(UserChange::DeOperator)));
 /// assert_eq!(query.next(), Some(Err(Error::Unknown('X', false))));
 /// assert_eq!(query.next(), Some(Err(Error::Unchangeable('a', false))));
+/// assert_eq!(query.next(), Some(Ok(UserChange::ServerNotices(false))));
 /// assert_eq!(query.next(), None);
 /// ```
 pub fn user_query(modes: &str) -> impl Iterator<Item = Result<UserChange>> + '_ {
     SimpleQuery::new(modes).map(|(value, mode)| match mode {
         'i' => Ok(UserChange::Invisible(value)),
         'o' if !value => Ok(UserChange::DeOperator),
+        's' => Ok(UserChange::ServerNotices(value)),
         other if USER_MODES.contains(other) => Err(Error::Unchangeable(other, value)),
         other => Err(Error::Unknown(other, value)),
     })
@@ -12,8 +12,18 @@ pub const YOURHOST: &str = "002"; // :Your host is...
 pub const CREATED: &str = "003"; // :This server was created...
 pub const MYINFO: &str = "004"; // <servername> <version> <umodes> <chan modes> <chan modes with a parameter>
 pub const ISUPPORT: &str = "005"; // 1*13<TOKEN[=value]> :are supported by this server
+pub const SNOMASK: &str = "008"; // <snomask> :Server notice mask
 
+pub const STATSLINKINFO: &str = "211"; // <linkname> <sendq> <sent msgs> <sent KiB> <recv msgs> <recv KiB> <time open>
+pub const STATSCOMMANDS: &str = "212"; // <command> <count> <byte count> <remote count>
+pub const STATSKLINE: &str = "216"; // K <mask> * * :<reason>
+pub const ENDOFSTATS: &str = "219"; // <stats letter> :End of STATS report
+pub const STATSPLINE: &str = "220"; // P <address> <clients> <flags>
 pub const UMODEIS: &str = "221"; // <modes>
+pub const STATSDLINE: &str = "225"; // D <mask> :<reason>
+pub const STATSUPTIME: &str = "242"; // :Server Up <days> days <hours>:<minutes>:<seconds>
+pub const STATSOLINE: &str = "243"; // O <hostmask> * <name>
+pub const STATSDEBUG: &str = "249"; // :<info>
 pub const LUSERCLIENT: &str = "251"; // :<int> users and <int> services on <int> servers
 pub const LUSEROP: &str = "252"; // <int> :operator(s) online
 pub const LUSERUNKNOWN: &str = "253"; // <int> :unknown connection(s)
@@ -23,6 +33,7 @@ pub const ADMINME: &str = "256"; // <server> :Admin info
 pub const ADMINLOC1: &str = "257"; // :<info>
 pub const ADMINLOC2: &str = "258"; // :<info>
 pub const ADMINMAIL: &str = "259"; // :<info>
+pub const WHOISCERTFP: &str = "276"; // <nick> :has client certificate fingerprint <fingerprint>
 
 pub const AWAY: &str = "301"; // <nick> :<away message>
 pub const UNAWAY: &str = "305"; // :You are no longer marked as being away
@@ -59,6 +70,7 @@ pub const ENDOFMOTD: &str = "376"; // :End of MOTD command
 pub const YOUREOPER: &str = "381"; // :You are now an operator
 pub const REHASHING: &str = "382"; // <config file> :Rehashing
 pub const TIME: &str = "391"; // <servername> :<time in whatever format>
+pub const HOSTHIDDEN: &str = "396"; // <host> :is now your displayed host
 
 pub const ERR_NOSUCHNICK: &str = "401"; // <nick> :No such nick/channel
 pub const ERR_NOSUCHCHANNEL: &str = "403"; // <channel> :No such channel
@@ -92,6 +104,8 @@ pub const ERR_CHANOPRIVSNEEDED: &str = "482"; // <channel> :You're not an operat
 pub const ERR_UMODEUNKNOWNFLAG: &str = "501"; // :Unknown mode flag
 pub const ERR_USERSDONTMATCH: &str = "502"; // :Can't change mode for other users
 
+pub const WHOISSECURE: &str = "671"; // <nick> :is using a secure connection
+
 pub const LOGGEDIN: &str = "900"; // <nick> <nick>!<ident>@<host> <account> :You are now logged in as <user>
 pub const LOGGEDOUT: &str = "901"; // <nick> <nick>!<ident>@<host> :You are now logged out
 pub const ERR_NICKLOCKED: &str = "902"; // :You must use a nick assigned to you
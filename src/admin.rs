@@ -0,0 +1,299 @@
+//! Admin control socket.
+//!
+//! When `control_socket` is set in the configuration, ellidri listens on this Unix socket for
+//! administration requests.  Only the user running ellidri can connect to it.
+//!
+//! Each request is a JSON object on its own line, with an "action" field (see `Request`).  The
+//! server answers each request with a JSON object on one line, which has an "ok" field, and an
+//! "error" field when "ok" is false.  For example:
+//!
+//! ```text
+//! > {"action":"kill","nick":"spammer","reason":"Go away"}
+//! < {"ok":true}
+//! > {"action":"ban","mask":"*!*@192.0.2.*"}
+//! < {"killed":3,"ok":true}
+//! ```
+//!
+//! `ellidri ctl` is a client for this socket, see `ctl`.
+
+use crate::State;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// The socket used by `ellidri ctl` when none is given.
+pub const DEFAULT_SOCKET: &str = "/run/ellidri/control.sock";
+
+pub const CTL_USAGE: &str = "Actions:
+    rehash              reload the configuration file
+    clients             list connected clients
+    channels            list channels
+    stats               show server statistics
+    kill NICK [REASON]  disconnect a client
+    ban MASK [REASON]   ban a nick!user@host mask and disconnect matching clients
+    unban MASK          remove a ban
+    bans                list bans, saved to bans_file if it is set
+    notice TEXT         send a notice to all clients";
+
+/// A request sent to the admin socket.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Request {
+    Rehash,
+    Clients,
+    Channels,
+    Stats,
+    Kill {
+        nick: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Ban {
+        mask: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Unban {
+        mask: String,
+    },
+    Bans,
+    Notice {
+        text: String,
+    },
+}
+
+impl Request {
+    /// Builds a request from the arguments of `ellidri ctl`, or returns `None` if they are
+    /// invalid.
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        let (action, params) = args.split_first()?;
+        let reason = || Some(params[1..].join(" ")).filter(|reason| !reason.is_empty());
+        let req = match (action.as_str(), params.len()) {
+            ("rehash", 0) => Self::Rehash,
+            ("clients", 0) => Self::Clients,
+            ("channels", 0) => Self::Channels,
+            ("stats", 0) => Self::Stats,
+            ("bans", 0) => Self::Bans,
+            ("kill", n) if 1 <= n => Self::Kill {
+                nick: params[0].clone(),
+                reason: reason(),
+            },
+            ("ban", n) if 1 <= n => Self::Ban {
+                mask: params[0].clone(),
+                reason: reason(),
+            },
+            ("unban", 1) => Self::Unban {
+                mask: params[0].clone(),
+            },
+            ("notice", n) if 1 <= n => Self::Notice {
+                text: params.join(" "),
+            },
+            _ => return None,
+        };
+        Some(req)
+    }
+}
+
+fn ok() -> Value {
+    json!({ "ok": true })
+}
+
+fn error(text: impl ToString) -> Value {
+    json!({ "ok": false, "error": text.to_string() })
+}
+
+/// Name given to the admin socket in the audit log.
+const AUDIT_BY: &str = "control socket";
+
+/// Handles a request and returns the response.
+async fn handle_request(req: Request, shared: &State, rehash: &Notify) -> Value {
+    log::debug!("admin: {:?}", req);
+    match req {
+        Request::Rehash => {
+            shared.audit("rehash", json!({ "by": AUDIT_BY })).await;
+            rehash.notify();
+            ok()
+        }
+        Request::Clients => json!({ "ok": true, "clients": shared.admin_clients().await }),
+        Request::Channels => json!({ "ok": true, "channels": shared.admin_channels().await }),
+        Request::Stats => json!({ "ok": true, "stats": shared.admin_stats().await }),
+        Request::Kill { nick, reason } => {
+            let reason = reason.as_deref().unwrap_or("Killed by the administrator");
+            let killed = shared.kill(&nick, reason).await;
+            shared.audit("kill", json!({ "by": AUDIT_BY, "target": nick, "reason": reason, "success": killed })).await;
+            if killed {
+                ok()
+            } else {
+                error(format_args!("no such nick: {}", nick))
+            }
+        }
+        Request::Ban { mask, reason } => {
+            let reason = reason.unwrap_or_else(|| String::from("Banned by the administrator"));
+            let audit = json!({ "by": AUDIT_BY, "mask": mask, "reason": reason });
+            shared.audit("ban", audit).await;
+            let killed = shared.add_ban(mask, reason).await;
+            shared.save_bans().await;
+            json!({ "ok": true, "killed": killed })
+        }
+        Request::Unban { mask } => {
+            let removed = shared.remove_ban(&mask).await;
+            shared.audit("unban", json!({ "by": AUDIT_BY, "mask": mask, "success": removed })).await;
+            if removed {
+                shared.save_bans().await;
+                ok()
+            } else {
+                error(format_args!("no such ban: {}", mask))
+            }
+        }
+        Request::Bans => json!({ "ok": true, "bans": shared.bans().await }),
+        Request::Notice { text } => {
+            let sent = shared.send_global_notice(&text).await;
+            json!({ "ok": true, "sent": sent })
+        }
+    }
+}
+
+/// Handles the requests of an admin connection, until it is closed.
+#[cfg(unix)]
+async fn handle_conn(
+    conn: tokio::net::UnixStream,
+    shared: State,
+    rehash: Arc<Notify>,
+) -> std::io::Result<()> {
+    use tokio::io::{self, AsyncBufReadExt as _, AsyncWriteExt as _};
+
+    let (reader, mut writer) = io::split(conn);
+    let mut lines = io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(req) => handle_request(req, &shared, &rehash).await,
+            Err(err) => error(err),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Listens on `path` and serves admin requests.
+///
+/// `rehash` is notified when a rehash is requested.
+#[cfg(unix)]
+pub async fn serve(path: PathBuf, shared: State, rehash: Arc<Notify>) {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+    use std::os::unix::net::UnixListener;
+
+    let bind = || -> std::io::Result<_> {
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let ln = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        ln.set_nonblocking(true)?;
+        tokio::net::UnixListener::from_std(ln)
+    };
+    let mut ln = match bind() {
+        Ok(ln) => ln,
+        Err(err) => {
+            log::error!("Admin socket {} failed to come online: {}", path.display(), err);
+            return;
+        }
+    };
+    log::info!("Admin socket {} online", path.display());
+
+    loop {
+        match ln.accept().await {
+            Ok((conn, _)) => {
+                let conn = handle_conn(conn, shared.clone(), rehash.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        log::warn!("Admin connection failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => log::warn!("Admin socket failed to accept a connection: {}", err),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(path: PathBuf, _: State, _: Arc<Notify>) {
+    log::error!("Admin socket {} is not supported on this platform", path.display());
+}
+
+/// Sends `req` to the admin socket at `path` and prints the response.
+///
+/// Returns whether the request succeeded.
+#[cfg(unix)]
+pub fn ctl(path: &Path, req: &Request) -> bool {
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::os::unix::net::UnixStream;
+
+    let send = || -> std::io::Result<Value> {
+        let mut conn = UnixStream::connect(path)?;
+        let mut line = serde_json::to_string(req)?;
+        line.push('\n');
+        conn.write_all(line.as_bytes())?;
+
+        let mut response = String::new();
+        BufReader::new(conn).read_line(&mut response)?;
+        Ok(serde_json::from_str(&response)?)
+    };
+    let response = match send() {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            return false;
+        }
+    };
+
+    if let Some(error) = response["error"].as_str() {
+        eprintln!("error: {}", error);
+    } else if let Ok(pretty) = serde_json::to_string_pretty(&response) {
+        println!("{}", pretty);
+    }
+    response["ok"].as_bool() == Some(true)
+}
+
+#[cfg(not(unix))]
+pub fn ctl(_: &Path, _: &Request) -> bool {
+    eprintln!("The admin socket is not supported on this platform");
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_request_from_args() {
+        let req = Request::from_args(&args("kill spammer go away")).unwrap();
+        assert_eq!(
+            serde_json::to_value(&req).unwrap(),
+            json!({ "action": "kill", "nick": "spammer", "reason": "go away" })
+        );
+
+        let req = Request::from_args(&args("ban *!*@192.0.2.*")).unwrap();
+        assert_eq!(
+            serde_json::to_value(&req).unwrap(),
+            json!({ "action": "ban", "mask": "*!*@192.0.2.*", "reason": null })
+        );
+
+        let req: Request = serde_json::from_str(r#"{"action":"kill","nick":"a"}"#).unwrap();
+        assert!(matches!(req, Request::Kill { reason: None, .. }));
+
+        assert!(Request::from_args(&args("stats now")).is_none());
+        assert!(Request::from_args(&args("unban")).is_none());
+        assert!(Request::from_args(&[]).is_none());
+    }
+} // mod tests
@@ -0,0 +1,80 @@
+//! Audit log.
+//!
+//! When `audit_log` is set in the configuration, privileged actions are appended to this file as
+//! JSON objects, one per line: OPER attempts, KILLs, server bans, channel mode changes made by
+//! operators, REHASH, DIE and RESTART.  Each entry has a "time", an "event" and, when the action
+//! comes from a client, the client identifier and its nick!user@host as "client" and "by".
+//!
+//! Entries are written regardless of `ELLIDRI_LOG`.  Writes happen on a dedicated thread, so that
+//! the shared state is never blocked on the disk.
+
+use serde_json::{Map, Value};
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+/// Handle to the audit log.  Entries are discarded when there is no audit log.
+#[derive(Default)]
+pub struct Log {
+    path: Option<PathBuf>,
+    entries: Option<mpsc::Sender<String>>,
+}
+
+impl Log {
+    /// Opens the audit log at `path` for appending, or returns a handle that discards entries if
+    /// `path` is `None` or cannot be opened.
+    pub fn open(path: Option<&Path>) -> Self {
+        let path = match path {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path);
+        let mut file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("Failed to open the audit log {:?}: {}", path.display(), err);
+                return Self::default();
+            }
+        };
+        log::info!("Writing the audit log to {:?}", path.display());
+
+        let (entries, rx) = mpsc::channel::<String>();
+        let display = path.display().to_string();
+        thread::spawn(move || {
+            for entry in rx {
+                if let Err(err) = file.write_all(entry.as_bytes()) {
+                    log::error!("Failed to write to the audit log {:?}: {}", display, err);
+                }
+            }
+        });
+
+        Self {
+            path: Some(path.to_owned()),
+            entries: Some(entries),
+        }
+    }
+
+    /// The path of the audit log, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Appends an entry for `event`, with the given fields.
+    pub fn write(&self, event: &str, fields: Value) {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => return,
+        };
+        let mut entry = Map::new();
+        entry.insert("time".to_owned(), Value::from(crate::util::time_precise()));
+        entry.insert("event".to_owned(), Value::from(event));
+        if let Value::Object(fields) = fields {
+            entry.extend(fields);
+        }
+        let mut line = Value::Object(entry).to_string();
+        line.push('\n');
+        let _ = entries.send(line);
+    }
+}
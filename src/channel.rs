@@ -35,6 +35,25 @@ impl MemberModes {
         }
     }
 
+    /// Pushes all the modes' letters to the given string, in decreasing order of rank.
+    pub fn all_letters(self, out: &mut String) {
+        if self.founder {
+            out.push('q');
+        }
+        if self.protected {
+            out.push('a');
+        }
+        if self.operator {
+            out.push('o');
+        }
+        if self.halfop {
+            out.push('h');
+        }
+        if self.voice {
+            out.push('v');
+        }
+    }
+
     /// Returns the highest enabled mode.
     pub fn symbol(self) -> Option<char> {
         if self.founder {
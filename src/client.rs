@@ -1,10 +1,15 @@
 //! Client data, connection state and capability logic.
 
+use crate::config::{BindAddress, Privilege};
+use crate::snomask::Snomask;
 use crate::{data, util};
 use ellidri_tokens::{mode, Buffer, MessageBuffer, ReplyBuffer};
 use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Instant;
+use tokio::sync::{mpsc, Notify};
 
 #[derive(Clone, Debug)]
 pub struct MessageQueueItem {
@@ -41,6 +46,75 @@ impl AsRef<str> for MessageQueueItem {
 
 pub type MessageQueue = mpsc::UnboundedSender<MessageQueueItem>;
 
+/// Accounting of the bytes waiting in a client's message queue.
+///
+/// It is shared between `Client`, which fills the queue, and the task that writes the queue to the
+/// connection.
+#[derive(Debug, Default)]
+pub struct SendQ {
+    len: AtomicUsize,
+    max: AtomicUsize,
+    exceeded: Notify,
+
+    /// Number of messages and bytes written to the connection.
+    sent_msgs: AtomicUsize,
+    sent_bytes: AtomicUsize,
+}
+
+impl SendQ {
+    /// The number of bytes waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Whether all queued messages have been written to the connection.
+    pub fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Relaxed) == 0
+    }
+
+    /// Must be called once `n` bytes of the queue have been written to the connection.
+    pub fn sent(&self, n: usize) {
+        self.len.fetch_sub(n, Ordering::Relaxed);
+        self.sent_msgs.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The number of messages and bytes written to the connection so far.
+    pub fn sent_total(&self) -> (usize, usize) {
+        let msgs = self.sent_msgs.load(Ordering::Relaxed);
+        let bytes = self.sent_bytes.load(Ordering::Relaxed);
+        (msgs, bytes)
+    }
+
+    /// Resolves once the client has been sent more than the maximum length of the queue.
+    pub async fn exceeded(&self) {
+        self.exceeded.notified().await;
+    }
+}
+
+/// Information about a connection, gathered before the client is added to the state.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    /// The address of the client.
+    pub addr: SocketAddr,
+
+    /// The address of the binding the client connected to.
+    pub binding: BindAddress,
+
+    /// Whether the client is connected through a Unix socket.  Local clients have a loopback
+    /// address and are not rate limited.
+    pub local: bool,
+
+    /// Whether the connection is encrypted with TLS.
+    pub tls: bool,
+
+    /// The negotiated TLS version and cipher suite.
+    pub tls_info: Option<String>,
+
+    /// The fingerprint of the client's TLS certificate, see `util::fingerprint`.
+    pub certfp: Option<String>,
+}
+
 /// A state machine that represent the connection with a client. It keeps track of what message the
 /// client can send.
 ///
@@ -70,7 +144,9 @@ impl ConnectionState {
         match self {
             ConnectionState::ConnectionEstablished => match request {
                 CapLs { .. } | CapReq { .. } => Ok(ConnectionState::CapGiven),
-                Authenticate { .. } | CapEnd | Pass { .. } | Ping { .. } => Ok(self),
+                Authenticate { .. } | CapEnd | Pass { .. } | Ping { .. } | WebIrc { .. } => {
+                    Ok(self)
+                }
                 Nick { .. } => Ok(ConnectionState::NickGiven),
                 User { .. } => Ok(ConnectionState::UserGiven),
                 Quit { .. } => Ok(ConnectionState::Quit),
@@ -78,7 +154,11 @@ impl ConnectionState {
             },
             ConnectionState::NickGiven => match request {
                 CapLs { .. } | CapReq { .. } => Ok(ConnectionState::CapGiven),
-                Authenticate { .. } | CapEnd | Nick { .. } | Pass { .. } | Ping { .. } => Ok(self),
+                Authenticate { .. }
+                | CapEnd
+                | Nick { .. }
+                | Pass { .. }
+                | Ping { .. } => Ok(self),
                 User { .. } => Ok(ConnectionState::Registered),
                 Quit { .. } => Ok(ConnectionState::Quit),
                 _ => Err(()),
@@ -92,9 +172,11 @@ impl ConnectionState {
             },
             ConnectionState::CapGiven => match request {
                 CapEnd => Ok(ConnectionState::ConnectionEstablished),
-                Authenticate { .. } | CapLs { .. } | CapReq { .. } | Pass { .. } | Ping { .. } => {
-                    Ok(self)
-                }
+                Authenticate { .. }
+                | CapLs { .. }
+                | CapReq { .. }
+                | Pass { .. }
+                | Ping { .. } => Ok(self),
                 Nick { .. } => Ok(ConnectionState::CapNickGiven),
                 User { .. } => Ok(ConnectionState::CapUserGiven),
                 Quit { .. } => Ok(ConnectionState::Quit),
@@ -114,9 +196,11 @@ impl ConnectionState {
             },
             ConnectionState::CapUserGiven => match request {
                 CapEnd => Ok(ConnectionState::UserGiven),
-                Authenticate { .. } | CapLs { .. } | CapReq { .. } | Pass { .. } | Ping { .. } => {
-                    Ok(self)
-                }
+                Authenticate { .. }
+                | CapLs { .. }
+                | CapReq { .. }
+                | Pass { .. }
+                | Ping { .. } => Ok(self),
                 Nick { .. } => Ok(ConnectionState::CapNegotiation),
                 Quit { .. } => Ok(ConnectionState::Quit),
                 _ => Err(()),
@@ -133,7 +217,7 @@ impl ConnectionState {
                 _ => Err(()),
             },
             ConnectionState::Registered => match request {
-                Pass { .. } | User { .. } => Err(()),
+                Pass { .. } | User { .. } | WebIrc { .. } => Err(()),
                 Quit { .. } => Ok(ConnectionState::Quit),
                 _ => Ok(self),
             },
@@ -158,6 +242,7 @@ pub struct Client {
     /// This is the write end of a mpsc channel of messages (similar to go channels). It is
     /// currently unbounded, meaning sending messages to this channel does not block.
     queue: MessageQueue,
+    sendq: Arc<SendQ>,
 
     pub domain: Arc<str>,
 
@@ -175,6 +260,36 @@ pub struct Client {
     host: String,
     account: Option<String>,
 
+    /// The host of the client before it is cloaked or replaced by a vhost.
+    real_host: String,
+
+    /// The host set by OPER or CHGHOST, shown instead of the real or cloaked host.
+    pub vhost: Option<String>,
+
+    /// The IP address the client is connected from.
+    ip: IpAddr,
+
+    /// The address of the binding the client connected to.
+    binding: BindAddress,
+    tls: bool,
+    tls_info: Option<String>,
+
+    /// The index of the client's connection class in the server configuration.
+    pub class: Option<usize>,
+
+    /// The time of the last message sent by the client.
+    pub last_seen: Instant,
+
+    /// Number of messages and bytes received from the client.
+    pub recv_msgs: usize,
+    pub recv_bytes: usize,
+
+    /// The fingerprint of the client's TLS certificate.
+    certfp: Option<String>,
+
+    /// The name of the WEBIRC gateway the client connected through.
+    pub gateway: Option<String>,
+
     /// The nick!user@host
     full_name: String,
 
@@ -184,13 +299,37 @@ pub struct Client {
     /// The time of the last action
     last_action_time: u64,
 
-    /// Whether the client has issued a PASS command with the right password.
-    pub has_given_password: bool,
+    /// The stored password the client has given with PASS, so that it is checked again if the
+    /// client changes class.
+    pub given_password: Option<String>,
+
+    /// Number of failed OPER and PASS attempts, see `lockout`.
+    pub auth_failures: u32,
 
     // Modes: https://tools.ietf.org/html/rfc2812.html#section-3.1.5
     pub away_message: Option<String>,
     pub invisible: bool,
     pub operator: bool,
+
+    /// The class and the vhost of the `opers` entry used by OPER, so that privileges can be
+    /// updated on rehash and the vhost removed with the operator mode.
+    pub oper_class: Option<String>,
+    pub oper_vhost: Option<String>,
+
+    /// What the client can do as an operator, set by OPER.
+    pub privileges: Vec<Privilege>,
+
+    /// The server notices the client receives, see `snomask`.  `+s` is set when not empty.
+    pub snomask: Snomask,
+
+    /// The server notices the client is allowed to receive, set by OPER.
+    pub allowed_snomask: Snomask,
+
+    /// Whether the client has hit its rate limit, so that it is only reported once.
+    pub flooding: bool,
+
+    /// Whether the client is connected through a Unix socket, see `Peer::local`.
+    pub local: bool,
 }
 
 impl Client {
@@ -198,10 +337,13 @@ impl Client {
     ///
     /// The nickname is set to "*", as it seems it's what freenode server does.  The username and
     /// the realname are set to empty strings.
-    pub fn new(domain: Arc<str>, queue: MessageQueue, host: String) -> Self {
+    pub fn new(domain: Arc<str>, queue: MessageQueue, sendq: Arc<SendQ>, peer: Peer) -> Self {
         let now = util::time();
+        let ip = peer.addr.ip();
+        let host = if peer.local { String::from("localhost") } else { ip.to_string() };
         Self {
             queue,
+            sendq,
             domain,
             full_name: String::with_capacity(FULL_NAME_LENGTH),
             cap_version: data::cap::Version::V300,
@@ -213,14 +355,34 @@ impl Client {
             nick: String::from("*"),
             user: String::new(),
             real: String::new(),
-            host,
+            host: host.clone(),
             account: None,
+            real_host: host,
+            vhost: None,
+            ip,
+            binding: peer.binding,
+            tls: peer.tls,
+            tls_info: peer.tls_info,
+            class: None,
+            last_seen: Instant::now(),
+            recv_msgs: 0,
+            recv_bytes: 0,
+            certfp: peer.certfp,
+            gateway: None,
             signon_time: now,
             last_action_time: now,
-            has_given_password: false,
+            given_password: None,
+            auth_failures: 0,
             away_message: None,
             invisible: false,
             operator: false,
+            oper_class: None,
+            oper_vhost: None,
+            privileges: Vec::new(),
+            snomask: Snomask::default(),
+            allowed_snomask: Snomask::default(),
+            flooding: false,
+            local: peer.local,
         }
     }
 
@@ -232,9 +394,26 @@ impl Client {
         if self.cap_enabled.has_message_tags() {
             msg.start = 0;
         }
+        let len = msg.as_ref().len();
+        let max = self.sendq.max.load(Ordering::Relaxed);
+        let queued = self.sendq.len.fetch_add(len, Ordering::Relaxed) + len;
+        if max != 0 && max < queued {
+            self.sendq.len.fetch_sub(len, Ordering::Relaxed);
+            self.sendq.exceeded.notify();
+            return;
+        }
         let _ = self.queue.send(msg);
     }
 
+    pub fn sendq(&self) -> Arc<SendQ> {
+        self.sendq.clone()
+    }
+
+    /// Set the maximum number of bytes waiting to be sent to the client, 0 for unlimited.
+    pub fn set_sendq_max(&self, max: usize) {
+        self.sendq.max.store(max, Ordering::Relaxed);
+    }
+
     pub fn reply(&self, label: &str) -> ReplyBuffer {
         ReplyBuffer::new(&self.domain, &self.nick, label)
     }
@@ -355,6 +534,58 @@ impl Client {
         &self.host
     }
 
+    /// Change the displayed host of the client.
+    pub fn set_host(&mut self, host: &str) {
+        self.host.clear();
+        self.host.push_str(host);
+        self.update_full_name();
+    }
+
+    /// The host of the client, before it is cloaked or replaced by a vhost.
+    pub fn real_host(&self) -> &str {
+        &self.real_host
+    }
+
+    /// Change the real host of the client, e.g. when it connects through a gateway.  The
+    /// displayed host is not changed.
+    pub fn set_real_host(&mut self, host: &str) {
+        self.real_host.clear();
+        self.real_host.push_str(host);
+    }
+
+    /// The IP address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: IpAddr) {
+        self.ip = ip;
+    }
+
+    pub fn binding(&self) -> &BindAddress {
+        &self.binding
+    }
+
+    /// Whether the client is connected with TLS.
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Changes whether the client is connected with TLS, e.g. when it connects through a gateway.
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+        self.tls_info = None;
+    }
+
+    /// The negotiated TLS version and cipher suite, e.g. "TLSv1_3, TLS13_AES_128_GCM_SHA256".
+    pub fn tls_info(&self) -> Option<&str> {
+        self.tls_info.as_deref()
+    }
+
+    pub fn certfp(&self) -> Option<&str> {
+        self.certfp.as_deref()
+    }
+
     pub fn account(&self) -> Option<&str> {
         self.account.as_ref().map(|s| s.as_ref())
     }
@@ -391,6 +622,12 @@ impl Client {
         if self.operator {
             modes.push('o');
         }
+        if !self.snomask.is_empty() {
+            modes.push('s');
+        }
+        if self.tls {
+            modes.push('Z');
+        }
     }
 
     pub fn apply_mode_change(&mut self, change: mode::UserChange) -> bool {
@@ -404,8 +641,33 @@ impl Client {
             DeOperator => {
                 applied = self.operator;
                 self.operator = false;
+                self.oper_class = None;
+                self.oper_vhost = None;
+                self.privileges.clear();
+                self.snomask = Snomask::default();
+                self.allowed_snomask = Snomask::default();
+            }
+            ServerNotices(value) => {
+                applied = self.snomask.is_empty() == value;
+                self.snomask = if value { self.allowed_snomask } else { Snomask::default() };
             }
         }
         applied
     }
+
+    /// Applies the parameter of `+s`, within `allowed_snomask`.  Returns whether `+s` is set.
+    pub fn apply_snomask(&mut self, changes: Option<&str>) -> bool {
+        match changes {
+            Some(changes) => self.snomask.apply(changes),
+            None if self.snomask.is_empty() => self.snomask = self.allowed_snomask,
+            None => {}
+        }
+        self.snomask = self.snomask.intersection(self.allowed_snomask);
+        !self.snomask.is_empty()
+    }
+
+    /// Whether the client is an operator with the given privilege.
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.operator && self.privileges.contains(&privilege)
+    }
 }
@@ -4,8 +4,11 @@
 //!
 //! [1]: https://git.sr.ht/~taiite/ellidri/tree/master/doc/ellidri.conf
 
-use ellidri_tokens::mode;
+use crate::util::Cidr;
+use ellidri_tokens::{mode, Command};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::{fmt, fs, io, net, path};
 use tokio_rustls::webpki;
 
@@ -17,6 +20,9 @@ pub enum Error {
     Format(serde_yaml::Error),
     InvalidDomain,
     InvalidModes,
+    InvalidPermissions,
+    UnknownCommand(String),
+    UnknownOperClass(String),
 }
 
 impl std::error::Error for Error {
@@ -48,25 +54,137 @@ impl fmt::Display for Error {
             Self::Format(err) => err.fmt(f),
             Self::InvalidDomain => write!(f, "'domain' must be a domain name (e.g. irc.com)"),
             Self::InvalidModes => write!(f, "'default_chan_mode' must be a mode string (e.g. +nt)"),
+            Self::InvalidPermissions => write!(f, "'permissions' must be an octal mode (e.g. 660) on a Unix socket"),
+            Self::UnknownCommand(name) => write!(f, "'command_costs' has unknown command {:?}", name),
+            Self::UnknownOperClass(name) => write!(f, "'opers' has unknown class {:?}", name),
         }
     }
 }
 
 /// TLS-related and needed information for TLS bindings.
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct Tls {
     pub certificate: path::PathBuf,
     pub key: path::PathBuf,
     #[serde(default)]
     pub require_certificates: bool,
+
+    /// Certificates sent instead of `certificate` to clients that ask for their domain (SNI).
+    #[serde(default)]
+    pub sni: Vec<SniCertificate>,
+}
+
+/// A certificate and its key, used for the given domain.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct SniCertificate {
+    pub domain: String,
+    pub certificate: path::PathBuf,
+    pub key: path::PathBuf,
+}
+
+/// The address of a binding: either an IP address and a TCP port, or the path of a Unix socket.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddress {
+    Tcp(net::SocketAddr),
+    Unix(path::PathBuf),
+}
+
+impl BindAddress {
+    /// The TCP port of the address, if any.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Self::Tcp(addr) => Some(addr.port()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl From<net::SocketAddr> for BindAddress {
+    fn from(val: net::SocketAddr) -> Self {
+        Self::Tcp(val)
+    }
+}
+
+impl std::str::FromStr for BindAddress {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(Self::Tcp(addr))
+        } else if s.contains('/') {
+            Ok(Self::Unix(s.into()))
+        } else {
+            Err("expected an IP address and a port, or the path of a Unix socket")
+        }
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = &'static str;
+
+    fn try_from(val: String) -> std::result::Result<Self, Self::Error> {
+        val.parse()
+    }
 }
 
-/// Listening address + port + optional TLS settings.
+impl From<BindAddress> for String {
+    fn from(val: BindAddress) -> Self {
+        val.to_string()
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// Listening address + port + optional TLS and PROXY protocol settings.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Binding {
-    pub address: net::SocketAddr,
+    pub address: BindAddress,
     #[serde(flatten)]
     pub tls: Option<Tls>,
+
+    /// Whether connections from `trusted_proxies` start with a PROXY protocol header.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default = "trusted_proxies")]
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// The name of the socket passed by systemd (`FileDescriptorName=`) to use for this binding.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The permissions of the socket file, in octal, for Unix sockets.
+    #[serde(default)]
+    pub permissions: Option<String>,
+}
+
+impl Binding {
+    /// Returns the permissions of the socket file, or `None` if they are not set or invalid.
+    pub fn mode(&self) -> Option<u32> {
+        let permissions = self.permissions.as_ref()?;
+        u32::from_str_radix(permissions, 8).ok().filter(|&mode| mode <= 0o7777)
+    }
+
+    /// Returns the addresses that must send a PROXY header, or `None` if the PROXY protocol is
+    /// disabled.
+    pub fn proxies(&self) -> Option<std::sync::Arc<[Cidr]>> {
+        if self.proxy_protocol {
+            Some(self.trusted_proxies.clone().into())
+        } else {
+            None
+        }
+    }
+}
+
+fn trusted_proxies() -> Vec<Cidr> {
+    vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()]
 }
 
 /// OPER credentials
@@ -74,6 +192,299 @@ pub struct Binding {
 pub struct Oper {
     pub name: String,
     pub password: String,
+
+    /// The name of the operator's class, see `OperClass`.  Operators without a class have all
+    /// privileges.
+    #[serde(default)]
+    pub class: Option<String>,
+
+    /// `user@host` masks the operator must match.  Matches all clients when empty.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// When set, the operator must also use a TLS client certificate with this SHA-256
+    /// fingerprint.  Case and colons are ignored.
+    #[serde(default)]
+    pub certfp: Option<String>,
+
+    /// Whether the operator must be connected with TLS.
+    #[serde(default)]
+    pub require_tls: bool,
+
+    /// The host given to the operator on OPER.
+    #[serde(default)]
+    pub vhost: Option<String>,
+}
+
+/// What operators are allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privilege {
+    /// Use KILL.
+    Kill,
+
+    /// See server bans with STATS k and d.
+    Ban,
+
+    /// Use REHASH.
+    Rehash,
+
+    /// Use DIE and RESTART.
+    Die,
+
+    /// See secret channels, invisible users, TLS details and use STATS and WHO masks.
+    SeeHidden,
+
+    /// Change channel modes without being a channel operator.
+    OverrideChannel,
+
+    /// Not being rate limited.
+    BypassRatelimit,
+
+    /// Use SAJOIN, SAPART, SANICK and CHGHOST.
+    ManageUsers,
+}
+
+impl Privilege {
+    pub const ALL: [Self; 8] = [
+        Self::Kill,
+        Self::Ban,
+        Self::Rehash,
+        Self::Die,
+        Self::SeeHidden,
+        Self::OverrideChannel,
+        Self::BypassRatelimit,
+        Self::ManageUsers,
+    ];
+}
+
+/// A set of privileges, shared by operators.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OperClass {
+    pub name: String,
+
+    #[serde(default)]
+    pub privileges: Vec<Privilege>,
+
+    /// Letters of the server notices the operators can receive, see `snomask`.  All of them when
+    /// `None`.
+    #[serde(default)]
+    pub snomasks: Option<String>,
+}
+
+/// Flood control settings.
+///
+/// Each message costs a number of points to the client.  Clients can spend up to `burst` points at
+/// once, and get one point back every `rate` milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    #[serde(default = "rate_limit_rate")]
+    pub rate: u32,
+    #[serde(default = "rate_limit_burst")]
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            rate: rate_limit_rate(),
+            burst: rate_limit_burst(),
+        }
+    }
+}
+
+/// Connection throttling settings, see `throttle::Throttle`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Throttle {
+    /// Maximum number of connection attempts from the same subnet, per `window`.
+    #[serde(default = "throttle_attempts")]
+    pub attempts: usize,
+
+    /// Length of the sliding window, in milliseconds.
+    #[serde(default = "throttle_window")]
+    pub window: u64,
+
+    /// Maximum number of simultaneous connections from the same IP address.
+    #[serde(default = "throttle_max_connections")]
+    pub max_connections: usize,
+
+    /// Prefix lengths of the subnets connection attempts are counted for.
+    #[serde(default = "throttle_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "throttle_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+
+    /// Connections from these addresses are never throttled.
+    #[serde(default)]
+    pub exempt: Vec<Cidr>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            attempts: throttle_attempts(),
+            window: throttle_window(),
+            max_connections: throttle_max_connections(),
+            ipv4_prefix: throttle_ipv4_prefix(),
+            ipv6_prefix: throttle_ipv6_prefix(),
+            exempt: Vec::new(),
+        }
+    }
+}
+
+fn throttle_attempts() -> usize {
+    10
+}
+fn throttle_window() -> u64 {
+    60_000
+}
+fn throttle_max_connections() -> usize {
+    10
+}
+fn throttle_ipv4_prefix() -> u8 {
+    32
+}
+fn throttle_ipv6_prefix() -> u8 {
+    64
+}
+
+/// Brute-force protection settings, see `lockout::Lockout`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuthLockout {
+    /// Number of failed attempts after which the client is disconnected, 0 for unlimited.
+    #[serde(default = "auth_lockout_max_failures")]
+    pub max_failures: u32,
+
+    /// Time in milliseconds an IP address or an account must wait after a failed attempt.  It
+    /// doubles with each failure.
+    #[serde(default = "auth_lockout_delay")]
+    pub delay: u64,
+
+    /// Number of failed attempts after which an IP address or an account is locked out.
+    #[serde(default = "auth_lockout_failures")]
+    pub lockout_failures: u32,
+
+    /// Time in milliseconds an IP address or an account stays locked out.  Failed attempts are
+    /// forgotten after the same time without failures.
+    #[serde(default = "auth_lockout_duration")]
+    pub lockout_duration: u64,
+
+    /// Length of the prefix IPv6 addresses are grouped by, copied from `Throttle::ipv6_prefix`.
+    #[serde(skip, default = "throttle_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+impl Default for AuthLockout {
+    fn default() -> Self {
+        Self {
+            max_failures: auth_lockout_max_failures(),
+            delay: auth_lockout_delay(),
+            lockout_failures: auth_lockout_failures(),
+            ipv6_prefix: throttle_ipv6_prefix(),
+            lockout_duration: auth_lockout_duration(),
+        }
+    }
+}
+
+fn auth_lockout_max_failures() -> u32 {
+    3
+}
+fn auth_lockout_delay() -> u64 {
+    1000
+}
+fn auth_lockout_failures() -> u32 {
+    10
+}
+fn auth_lockout_duration() -> u64 {
+    600_000
+}
+
+/// A WEBIRC gateway, allowed to give the address of the clients it forwards.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WebIrc {
+    pub name: String,
+    pub password: String,
+
+    /// The addresses the gateway connects from.
+    pub hosts: Vec<Cidr>,
+}
+
+/// Strict Transport Security policy, advertised with the `sts` capability.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Sts {
+    /// The port clients must use for TLS.  Defaults to the port of the first TLS binding.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Number of seconds clients must keep the policy.
+    #[serde(default = "sts_duration")]
+    pub duration: u64,
+
+    /// Whether the server agrees to be in STS preload lists.
+    #[serde(default)]
+    pub preload: bool,
+}
+
+/// A connection class.
+///
+/// Clients are put in the first class that matches their connection, and are subject to the
+/// limits and settings of this class.  Matching criteria that are not set match every connection.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Class {
+    pub name: String,
+
+    /// Addresses the class applies to.  The class applies to all addresses when empty.
+    #[serde(default)]
+    pub hosts: Vec<Cidr>,
+
+    /// Whether the class applies to TLS connections only, or to plain-text connections only.
+    #[serde(default)]
+    pub tls: Option<bool>,
+
+    /// The address of the binding the class applies to.
+    #[serde(default)]
+    pub binding: Option<BindAddress>,
+
+    /// Whether the class applies to logged-in clients only, or to other clients only.
+    #[serde(default)]
+    pub account: Option<bool>,
+
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    #[serde(default)]
+    pub max_clients_per_ip: Option<usize>,
+
+    /// Overrides the server password for this class.
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Overrides the global `sendq`, `rate_limit` and `ping_frequency` settings.
+    #[serde(default)]
+    pub sendq: Option<usize>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub ping_frequency: Option<u64>,
+
+    /// Whether the hosts of clients in this class are cloaked.
+    #[serde(default)]
+    pub cloak: bool,
+}
+
+impl Class {
+    /// Whether a connection with the given properties belongs to this class.
+    pub fn matches(
+        &self,
+        ip: net::IpAddr,
+        tls: bool,
+        binding: &BindAddress,
+        logged_in: bool,
+    ) -> bool {
+        (self.hosts.is_empty() || self.hosts.iter().any(|cidr| cidr.contains(ip)))
+            && self.tls.unwrap_or(tls) == tls
+            && self.binding.as_ref().filter(|b| *b != binding).is_none()
+            && self.account.unwrap_or(logged_in) == logged_in
+    }
 }
 
 /// Settings for `State`.
@@ -94,6 +505,12 @@ pub struct State {
     #[serde(default)]
     pub opers: Vec<Oper>,
 
+    #[serde(default)]
+    pub oper_classes: Vec<OperClass>,
+
+    #[serde(default)]
+    pub auth_lockout: AuthLockout,
+
     #[serde(default = "org")]
     pub org_name: String,
     #[serde(default = "org")]
@@ -120,6 +537,51 @@ pub struct State {
 
     #[serde(default = "login_timeout")]
     pub login_timeout: u64,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+
+    /// Overrides the number of points each command costs, by command name.
+    #[serde(default)]
+    pub command_costs: HashMap<String, u32>,
+
+    /// Clients from these addresses are not rate limited.
+    #[serde(default)]
+    pub rate_limit_exempt: Vec<Cidr>,
+
+    #[serde(default)]
+    pub classes: Vec<Class>,
+
+    /// Maximum number of bytes waiting to be sent to a client, 0 for unlimited.
+    #[serde(default = "sendq")]
+    pub sendq: usize,
+
+    /// Number of milliseconds of inactivity after which clients are sent a PING.
+    #[serde(default = "ping_frequency")]
+    pub ping_frequency: u64,
+
+    /// Secret used to compute cloaked hosts.  Generated at random when empty.
+    #[serde(default)]
+    pub cloak_key: String,
+
+    #[serde(default)]
+    pub webirc: Vec<WebIrc>,
+
+    #[serde(default)]
+    pub sts: Option<Sts>,
+
+    /// Sent to clients when the server shuts down.
+    #[serde(default = "shutdown_message")]
+    pub shutdown_message: String,
+
+    /// The file privileged actions are appended to, see `audit`.  Disabled when `None`.
+    #[serde(default)]
+    pub audit_log: Option<path::PathBuf>,
+
+    /// The file server bans are saved to, and loaded from at startup.  Bans are lost on shutdown
+    /// when `None`.
+    #[serde(default)]
+    pub bans_file: Option<path::PathBuf>,
 }
 
 /// The whole configuration.
@@ -134,14 +596,29 @@ pub struct Config {
     #[serde(default)]
     pub workers: usize,
 
+    #[serde(default)]
+    pub throttle: Throttle,
+
+    /// The path of the admin socket, see `admin`.  Disabled when `None`.
+    #[serde(default)]
+    pub control_socket: Option<path::PathBuf>,
+
+    /// The address of the HTTP binding that serves metrics, see `metrics`.  Disabled when `None`.
+    #[serde(default)]
+    pub metrics_binding: Option<net::SocketAddr>,
+
     #[serde(flatten)]
     pub state: State,
 }
 
 fn bindings() -> Vec<Binding> {
     vec![Binding {
-        address: net::SocketAddr::from(([127, 0, 0, 1], 6667)),
+        address: net::SocketAddr::from(([127, 0, 0, 1], 6667)).into(),
         tls: None,
+        proxy_protocol: false,
+        trusted_proxies: trusted_proxies(),
+        name: None,
+        permissions: None,
     }]
 }
 
@@ -184,6 +661,24 @@ fn userlen() -> usize {
 fn login_timeout() -> u64 {
     60_000
 }
+fn rate_limit_rate() -> u32 {
+    125
+}
+fn rate_limit_burst() -> u32 {
+    32
+}
+fn sendq() -> usize {
+    1_048_576
+}
+fn ping_frequency() -> u64 {
+    120_000
+}
+fn sts_duration() -> u64 {
+    2_592_000
+}
+fn shutdown_message() -> String {
+    String::from("Server shutting down")
+}
 
 fn db_max_size() -> u32 {
     10
@@ -203,6 +698,8 @@ impl State {
             motd_file: motd_file(),
             password: String::new(),
             opers: Vec::new(),
+            oper_classes: Vec::new(),
+            auth_lockout: AuthLockout::default(),
             org_name: org(),
             org_location: org(),
             org_mail: org(),
@@ -215,6 +712,18 @@ impl State {
             topiclen: topiclen(),
             userlen: userlen(),
             login_timeout: login_timeout(),
+            rate_limit: RateLimit::default(),
+            command_costs: HashMap::new(),
+            rate_limit_exempt: Vec::new(),
+            classes: Vec::new(),
+            sendq: sendq(),
+            ping_frequency: ping_frequency(),
+            cloak_key: String::new(),
+            webirc: Vec::new(),
+            sts: None,
+            shutdown_message: shutdown_message(),
+            audit_log: None,
+            bans_file: None,
         }
     }
 }
@@ -225,6 +734,9 @@ impl Config {
             is_unsafe: false,
             bindings: bindings(),
             workers: 0,
+            throttle: Throttle::default(),
+            control_socket: None,
+            metrics_binding: None,
             state: State::sample(),
         }
     }
@@ -232,7 +744,7 @@ impl Config {
     /// Reads the configuration file at the given path.
     pub fn from_file(path: impl AsRef<path::Path>) -> Result<Self> {
         let contents = fs::read_to_string(path)?;
-        let res: Self = serde_yaml::from_str(&contents)?;
+        let mut res: Self = serde_yaml::from_str(&contents)?;
 
         if webpki::DNSNameRef::try_from_ascii_str(&res.state.domain).is_err() {
             return Err(Error::InvalidDomain);
@@ -242,6 +754,33 @@ impl Config {
             return Err(Error::InvalidModes);
         }
 
+        let invalid_permissions = |b: &Binding| {
+            b.permissions.is_some()
+                && (b.mode().is_none() || !matches!(b.address, BindAddress::Unix(_)))
+        };
+        if res.bindings.iter().any(invalid_permissions) {
+            return Err(Error::InvalidPermissions);
+        }
+
+        if let Some(name) = res.state.command_costs.keys().find(|name| Command::parse(name).is_none()) {
+            return Err(Error::UnknownCommand(name.clone()));
+        }
+
+        let classes = &res.state.oper_classes;
+        let unknown_class = res.state.opers.iter().filter_map(|o| o.class.as_ref()).find(|name| {
+            !classes.iter().any(|class| class.name == **name)
+        });
+        if let Some(name) = unknown_class {
+            return Err(Error::UnknownOperClass(name.clone()));
+        }
+
+        res.state.auth_lockout.ipv6_prefix = res.throttle.ipv6_prefix;
+
+        // Fingerprints are compared with the output of `util::fingerprint`.
+        for certfp in res.state.opers.iter_mut().filter_map(|o| o.certfp.as_mut()) {
+            *certfp = certfp.chars().filter(|&c| c != ':').collect::<String>().to_ascii_lowercase();
+        }
+
         Ok(res)
     }
 }
@@ -16,6 +16,9 @@
 //! - A command channel:  bindings accept commands that change their configuration.  All commands
 //!   are described in the `Command` enum.
 //!
+//! When `control_socket` or `metrics_binding` are set, `Control` also runs the admin socket (see
+//! `admin::serve`) or the metrics binding (see `metrics::serve`) until ellidri exits.
+//!
 //! # The configuration file
 //!
 //! ellidri reads a configuration file at startup.  This configuration file is meant to specify its
@@ -36,19 +39,41 @@
 //!   command to it, either to make it listen for raw TCP connections, or to listen for TLS
 //!   connections with a given `TlsAcceptor` (see `tokio-tls` doc for that).
 //!
-//! Bindings are identified by their socket address (IP address + TCP port).  TLS identities are
-//! not kept track of, thus ellidri might reload the same TLS identity for a binding (it is fine to
-//! let it do we are not reading thousands for TLS identities here).
+//! Bindings are identified by their address (IP address + TCP port, or path of a Unix socket).
+//! TLS identities are not kept track of, thus ellidri might reload the same TLS identity for a
+//! binding (it is fine to let it do we are not reading thousands for TLS identities here).
+//!
+//! # TLS certificates
+//!
+//! `Control` also checks periodically the modification time of the certificates and keys used by
+//! the bindings.  When they change, the TLS identity is reloaded and sent to the binding with a
+//! `UseTls` command.  If it cannot be loaded, the binding keeps its old identity.
+//!
+//! # Shutdown
+//!
+//! Upon receiving a SIGTERM or SIGINT signal, or a DIE command, `Control` stops all bindings,
+//! disconnects all clients and waits for their outgoing messages to be sent before exiting, for
+//! at most `SHUTDOWN_TIMEOUT_SECS` seconds.
+//!
+//! # Restart
+//!
+//! Upon receiving a SIGUSR2 signal or a RESTART command, `Control` asks each binding for a copy
+//! of its listening socket, and starts a new ellidri process that inherits them (with the
+//! `LISTEN_FDS` protocol of systemd).  If the new process is still running after
+//! `RESTART_CHECK_SECS` seconds, this one shuts down and clients are asked to reconnect.
+//! Otherwise, the restart is cancelled.
 
-use crate::{Config, net, State};
-use crate::config::{Binding, Tls};
+use crate::{admin, metrics, snomask, Config, net, systemd, State};
+use crate::throttle::Throttle;
+use crate::util::Cidr;
+use crate::config::{BindAddress, Binding, Tls};
 use std::future::Future;
-use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{fs, process};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, iter, process};
 use tokio::runtime as rt;
-use tokio::sync::{mpsc, Notify};
-use tokio::task;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::{task, time};
 
 #[cfg(unix)]
 use tokio::signal::unix;
@@ -60,17 +85,54 @@ pub enum Command {
 
     /// Ask the binding task to listen for TLS connections with the given acceptor.
     UseTls(Arc<tokio_rustls::TlsAcceptor>),
+
+    /// Ask the binding task to send a copy of its listening socket.
+    ShareListener(oneshot::Sender<io::Result<net::StdListener>>),
+
+    /// Ask the binding task to expect a PROXY header from the given addresses, or to disable the
+    /// PROXY protocol.
+    SetProxies(Option<Arc<[Cidr]>>),
+}
+
+/// Number of seconds between two checks of the TLS files.
+const TLS_WATCH_INTERVAL_SECS: u64 = 60;
+
+/// Maximum number of seconds to wait for the messages to clients to be sent on shutdown.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+
+/// Number of seconds the new process must survive for a restart to succeed.
+const RESTART_CHECK_SECS: u64 = 2;
+
+/// Listening sockets inherited from the parent process or from systemd, with their name and
+/// address.
+#[derive(Default)]
+pub struct Listeners(Vec<(String, BindAddress, net::StdListener)>);
+
+impl Listeners {
+    /// Takes the socket for `binding`, matched by name or else by address.
+    fn take(&mut self, binding: &Binding) -> Option<net::StdListener> {
+        let by_name = binding.name.as_ref().and_then(|name| {
+            self.0.iter().position(|(ln_name, _, _)| ln_name == name)
+        });
+        let i = by_name.or_else(|| {
+            self.0.iter().position(|(_, address, _)| *address == binding.address)
+        })?;
+        Some(self.0.swap_remove(i).2)
+    }
 }
 
 /// A binding task that is ready to be spawned on the runtime.
 struct LoadedBinding<F> {
     /// The address to be bound.
-    address: SocketAddr,
+    address: BindAddress,
 
     /// Either `None` when the binding listens for raw TCP connections, or `Some(acceptor)` when the
     /// bindings listens for TLS connections with `acceptor`.
     acceptor: Option<Arc<tokio_rustls::TlsAcceptor>>,
 
+    /// The addresses that must send a PROXY header, see `Binding::proxies`.
+    proxies: Option<Arc<[Cidr]>>,
+
     /// The sending end of the channel that brings commands to the task.
     handle: mpsc::Sender<Command>,
 
@@ -78,6 +140,109 @@ struct LoadedBinding<F> {
     future: F,
 }
 
+/// The TLS files of a binding, and their modification time when they were last loaded.
+#[derive(Clone)]
+struct WatchedTls {
+    address: BindAddress,
+    tls: Tls,
+    mtimes: Vec<Option<SystemTime>>,
+}
+
+/// Returns the modification time of each file used by `tls`.
+fn tls_mtimes(tls: &Tls) -> Vec<Option<SystemTime>> {
+    let sni = tls
+        .sni
+        .iter()
+        .flat_map(|sni| iter::once(&sni.certificate).chain(iter::once(&sni.key)));
+    iter::once(&tls.certificate)
+        .chain(iter::once(&tls.key))
+        .chain(sni)
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Starts watching the TLS files of the given bindings.
+fn watch_tls(bindings: &[Binding]) -> Vec<WatchedTls> {
+    bindings
+        .iter()
+        .filter_map(|binding| {
+            let tls = binding.tls.clone()?;
+            let mtimes = tls_mtimes(&tls);
+            Some(WatchedTls { address: binding.address.clone(), tls, mtimes })
+        })
+        .collect()
+}
+
+/// Reloads the TLS identities whose files have changed.
+///
+/// Returns the new acceptors along with the address of their binding.  Identities that fail to
+/// load are tried again on the next call.
+fn reload_tls(watched: &mut [WatchedTls]) -> Vec<(BindAddress, Arc<tokio_rustls::TlsAcceptor>)> {
+    let mut res = Vec::new();
+    let mut store = net::TlsIdentityStore::default();
+
+    for w in watched {
+        let mtimes = tls_mtimes(&w.tls);
+        if mtimes == w.mtimes {
+            continue;
+        }
+        log::info!("TLS files of {} have changed, reloading", w.address);
+        match store.acceptor(&w.tls) {
+            Ok(acceptor) => {
+                w.mtimes = mtimes;
+                res.push((w.address.clone(), acceptor));
+            }
+            Err(_) => log::error!("Keeping the previous TLS identity of {}", w.address),
+        }
+    }
+
+    res
+}
+
+/// Tells the shared state which bindings are online and which of them use TLS.
+async fn update_bindings(
+    shared: &State,
+    bindings: &[(BindAddress, mpsc::Sender<Command>)],
+    watched: &[WatchedTls],
+) {
+    let bindings = bindings
+        .iter()
+        .map(|(address, _)| (address.clone(), watched.iter().any(|w| w.address == *address)))
+        .collect();
+    shared.set_bindings(bindings).await;
+}
+
+/// Reloads the TLS identities that have changed and sends them to their binding.
+async fn do_reload_tls(
+    watched: &mut Vec<WatchedTls>,
+    bindings: &mut [(BindAddress, mpsc::Sender<Command>)],
+) {
+    // Work on a copy, so that the files are still watched if the task fails.
+    let mut to_check = watched.clone();
+    let reloaded = task::spawn_blocking(move || {
+        let acceptors = reload_tls(&mut to_check);
+        (to_check, acceptors)
+    })
+    .await;
+    let acceptors = match reloaded {
+        Ok((checked, acceptors)) => {
+            *watched = checked;
+            acceptors
+        }
+        Err(err) => {
+            log::error!("Failed to reload TLS identities: {}", err);
+            return;
+        }
+    };
+
+    for (address, acceptor) in acceptors {
+        if let Some((_, handle)) = bindings.iter_mut().find(|(a, _)| *a == address) {
+            // If the binding task has stopped, it will be removed through `failures`.
+            let _ = handle.send(Command::UseTls(acceptor)).await;
+        }
+    }
+}
+
 /// Creates a tokio runtime with the given number of worker threads.
 fn create_runtime(workers: usize) -> rt::Runtime {
     let mut builder = rt::Builder::new();
@@ -107,29 +272,47 @@ fn create_runtime(workers: usize) -> rt::Runtime {
 fn load_bindings(
     bindings: Vec<Binding>,
     shared: &State,
-    stop: &mpsc::Sender<SocketAddr>,
-) -> Vec<(SocketAddr, mpsc::Sender<Command>)> {
+    throttle: &Throttle,
+    stop: &mpsc::Sender<BindAddress>,
+    inherited: &mut Listeners,
+) -> Vec<(BindAddress, mpsc::Sender<Command>)> {
     let mut res = Vec::with_capacity(bindings.len());
     let mut store = net::TlsIdentityStore::default();
 
-    for Binding { address, tls } in bindings {
+    for binding in bindings {
         let (handle, commands) = mpsc::channel(8);
-        if let Some(Tls { certificate, key, ..  }) = tls {
-            let acceptor = match store.acceptor(certificate, key) {
+        let address = binding.address.clone();
+        let proxies = binding.proxies();
+        if let Some(tls) = &binding.tls {
+            let acceptor = match store.acceptor(tls) {
                 Ok(acceptor) => acceptor,
                 Err(_) => process::exit(1),
             };
             let server = net::listen(
-                address,
+                address.clone(),
+                inherited.take(&binding),
+                binding.mode(),
                 shared.clone(),
+                throttle.clone(),
                 Some(acceptor),
+                proxies,
                 stop.clone(),
                 commands,
             );
             res.push((address, handle));
             tokio::spawn(server);
         } else {
-            let server = net::listen(address, shared.clone(), None, stop.clone(), commands);
+            let server = net::listen(
+                address.clone(),
+                inherited.take(&binding),
+                binding.mode(),
+                shared.clone(),
+                throttle.clone(),
+                None,
+                proxies,
+                stop.clone(),
+                commands,
+            );
             res.push((address, handle));
             tokio::spawn(server);
         }
@@ -149,23 +332,38 @@ fn load_bindings(
 async fn do_rehash(
     config_path: String,
     shared: &State,
-    stop: mpsc::Sender<SocketAddr>,
-    bindings: &mut Vec<(SocketAddr, mpsc::Sender<Command>)>,
+    throttle: &Throttle,
+    stop: mpsc::Sender<BindAddress>,
+    bindings: &mut Vec<(BindAddress, mpsc::Sender<Command>)>,
+    watched: &mut Vec<WatchedTls>,
 ) {
     log::info!("Reloading configuration from {:?}", config_path);
+    systemd::reloading();
     let shared_clone = shared.clone();
-    let reloaded = task::spawn_blocking(|| reload_config(config_path, shared_clone, stop)).await;
+    let throttle_clone = throttle.clone();
+    let reloaded = task::spawn_blocking(|| {
+        reload_config(config_path, shared_clone, throttle_clone, stop)
+    })
+    .await;
     let (cfg, new_bindings) = match reloaded {
-        Ok(Some(reloaded)) => reloaded,
-        _ => return,
+        Ok(Ok(reloaded)) => reloaded,
+        Ok(Err(err)) => {
+            shared.send_snotice(snomask::Kind::Rehash, format_args!("Rehash failed: {}", err)).await;
+            systemd::ready();
+            return;
+        }
+        Err(_) => {
+            systemd::ready();
+            return;
+        }
     };
 
     let mut i = 0;
     while i < bindings.len() {
-        let old_address = bindings[i].0;
+        let old_address = &bindings[i].0;
         if new_bindings
             .iter()
-            .all(|new_b| old_address != new_b.address)
+            .all(|new_b| *old_address != new_b.address)
         {
             bindings.swap_remove(i);
         } else {
@@ -175,13 +373,16 @@ async fn do_rehash(
 
     for new_b in new_bindings {
         if let Some(i) = bindings.iter().position(|old_b| old_b.0 == new_b.address) {
-            let res = bindings[i]
-                .1
+            let handle = &mut bindings[i].1;
+            let mut res = handle
                 .send(match new_b.acceptor {
                     Some(acceptor) => Command::UseTls(acceptor),
                     None => Command::UsePlain,
                 })
                 .await;
+            if res.is_ok() {
+                res = handle.send(Command::SetProxies(new_b.proxies)).await;
+            }
             if res.is_err() {
                 // Failure to send the command means either the binding task have dropped the
                 // command channel, or the binding task doesn't exist anymore.  Both possibilities
@@ -198,14 +399,21 @@ async fn do_rehash(
         }
     }
 
+    *watched = watch_tls(&cfg.bindings);
+    watched.retain(|w| bindings.iter().any(|(address, _)| *address == w.address));
+    throttle.rehash(cfg.throttle);
     shared.rehash(cfg.state).await;
+    update_bindings(shared, bindings, watched).await;
 
     log::info!("Configuration reloaded");
+    shared.send_snotice(snomask::Kind::Rehash, "Configuration reloaded").await;
+    systemd::ready();
 }
 
 /// Re-read the configuration file and re-generate the bindings.
 ///
-/// See documentation of `reload_bindings` for how bindings are re-generated.
+/// See documentation of `reload_bindings` for how bindings are re-generated.  Returns why the
+/// configuration file could not be read on failure.
 ///
 /// This function will put the contents of the MOTD file into `Config.motd_file`, so that the
 /// shared state can use the field as-is, since it must not use blocking operations such as reading
@@ -213,13 +421,14 @@ async fn do_rehash(
 fn reload_config(
     config_path: String,
     shared: State,
-    stop: mpsc::Sender<SocketAddr>,
-) -> Option<(Config, Vec<LoadedBinding<impl Future<Output = ()>>>)> {
+    throttle: Throttle,
+    stop: mpsc::Sender<BindAddress>,
+) -> Result<(Config, Vec<LoadedBinding<impl Future<Output = ()>>>), String> {
     let mut cfg = match Config::from_file(&config_path) {
         Ok(cfg) => cfg,
         Err(err) => {
             log::error!("Failed to read {:?}: {}", config_path, err);
-            return None;
+            return Err(format!("failed to read {:?}: {}", config_path, err));
         }
     };
     cfg.state.motd_file = match fs::read_to_string(&cfg.state.motd_file) {
@@ -229,8 +438,8 @@ fn reload_config(
             String::new()
         }
     };
-    let new_bindings = reload_bindings(&cfg.bindings, &shared, &stop);
-    Some((cfg, new_bindings))
+    let new_bindings = reload_bindings(&cfg.bindings, &shared, &throttle, &stop);
+    Ok((cfg, new_bindings))
 }
 
 /// Equivalent of `load_bindings` for when exiting the program is not acceptable.
@@ -243,36 +452,55 @@ fn reload_config(
 fn reload_bindings(
     bindings: &[Binding],
     shared: &State,
-    stop: &mpsc::Sender<SocketAddr>,
+    throttle: &Throttle,
+    stop: &mpsc::Sender<BindAddress>,
 ) -> Vec<LoadedBinding<impl Future<Output = ()>>> {
     let mut res = Vec::with_capacity(bindings.len());
     let mut store = net::TlsIdentityStore::default();
 
-    for Binding { address, tls } in bindings {
+    for binding in bindings {
         let (handle, commands) = mpsc::channel(8);
-        if let Some(Tls { certificate, key, ..  }) = tls {
-            let acceptor = match store.acceptor(certificate, key) {
+        let address = binding.address.clone();
+        let proxies = binding.proxies();
+        if let Some(tls) = &binding.tls {
+            let acceptor = match store.acceptor(tls) {
                 Ok(acceptor) => acceptor,
                 Err(_) => continue,
             };
             let future = net::listen(
-                *address,
+                address.clone(),
+                None,
+                binding.mode(),
                 shared.clone(),
+                throttle.clone(),
                 Some(acceptor.clone()),
+                proxies.clone(),
                 stop.clone(),
                 commands,
             );
             res.push(LoadedBinding {
-                address: *address,
+                address,
                 acceptor: Some(acceptor),
+                proxies,
                 handle,
                 future,
             });
         } else {
-            let future = net::listen(*address, shared.clone(), None, stop.clone(), commands);
+            let future = net::listen(
+                address.clone(),
+                None,
+                binding.mode(),
+                shared.clone(),
+                throttle.clone(),
+                None,
+                proxies.clone(),
+                stop.clone(),
+                commands,
+            );
             res.push(LoadedBinding {
-                address: *address,
+                address,
                 acceptor: None,
+                proxies,
                 handle,
                 future,
             });
@@ -282,18 +510,166 @@ fn reload_bindings(
     res
 }
 
+/// Takes the TCP or Unix listening socket at `index`.
+fn take_listener(fds: &mut listenfd::ListenFd, index: usize) -> io::Result<Option<net::StdListener>> {
+    #[cfg(unix)]
+    {
+        if let Ok(Some(ln)) = fds.take_unix_listener(index) {
+            return Ok(Some(net::StdListener::Unix(ln)));
+        }
+    }
+    Ok(fds.take_tcp_listener(index)?.map(net::StdListener::Tcp))
+}
+
+/// Takes the listening sockets passed by the parent process or by systemd, if any.
+///
+/// Must be called before the runtime is started, as it modifies environment variables.
+fn inherited_listeners() -> Listeners {
+    let mut res = Listeners::default();
+    let mut names = systemd::listen_fd_names().into_iter();
+    let mut fds = listenfd::ListenFd::from_env();
+
+    for i in 0..fds.len() {
+        let name = names.next().unwrap_or_default();
+        match take_listener(&mut fds, i) {
+            Ok(Some(ln)) => match ln.local_addr() {
+                Ok(address) => {
+                    log::info!("Using inherited socket {:?} on {}", name, address);
+                    res.0.push((name, address, ln));
+                }
+                Err(err) => log::warn!("Inherited socket #{} is unusable: {}", i, err),
+            },
+            Ok(None) => {}
+            Err(err) => log::warn!("Inherited socket #{} is unusable: {}", i, err),
+        }
+    }
+
+    res
+}
+
+/// Starts a new ellidri process with the same arguments, that inherits the given listening
+/// sockets.
+#[cfg(unix)]
+fn spawn_new_process(listeners: Vec<net::StdListener>) -> io::Result<process::Child> {
+    use command_fds::{CommandFdExt as _, FdMapping};
+
+    // Prefer the path ellidri was started with over `current_exe`, which points to the old
+    // binary if it has been replaced.
+    let mut args = env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => env::current_exe()?.into_os_string(),
+    };
+
+    let mut command = process::Command::new(program);
+    command
+        .args(args)
+        .env("LISTEN_FDS", listeners.len().to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS_FIRST_FD")
+        .env_remove("LISTEN_FDNAMES");
+    let mappings = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(i, ln)| FdMapping {
+            parent_fd: ln.into(),
+            child_fd: 3 + i as i32,
+        })
+        .collect();
+    command
+        .fd_mappings(mappings)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    command.spawn()
+}
+
+#[cfg(not(unix))]
+fn spawn_new_process(_: Vec<net::StdListener>) -> io::Result<process::Child> {
+    Err(io::Error::new(io::ErrorKind::Other, "not supported on this platform"))
+}
+
+/// Starts a new ellidri process that inherits the listening sockets of the bindings.
+///
+/// Returns whether the new process is running, in which case this one must shut down.
+async fn do_restart(bindings: &mut [(BindAddress, mpsc::Sender<Command>)]) -> bool {
+    log::info!("Restarting");
+
+    let mut listeners = Vec::with_capacity(bindings.len());
+    for (address, handle) in bindings.iter_mut() {
+        let (sender, receiver) = oneshot::channel();
+        if handle.send(Command::ShareListener(sender)).await.is_err() {
+            continue;
+        }
+        match receiver.await {
+            Ok(Ok(ln)) => listeners.push(ln),
+            Ok(Err(err)) => log::warn!("Failed to share the socket of {}: {}", address, err),
+            Err(_) => {}
+        }
+    }
+
+    let mut child = match spawn_new_process(listeners) {
+        Ok(child) => child,
+        Err(err) => {
+            log::error!("Failed to start the new process, restart cancelled: {}", err);
+            return false;
+        }
+    };
+
+    time::delay_for(Duration::from_secs(RESTART_CHECK_SECS)).await;
+    match child.try_wait() {
+        Ok(None) => {
+            systemd::main_pid(child.id());
+            true
+        }
+        Ok(Some(status)) => {
+            log::error!("The new process exited ({}), restart cancelled", status);
+            false
+        }
+        Err(err) => {
+            log::error!("Failed to check the new process, restart cancelled: {}", err);
+            false
+        }
+    }
+}
+
+/// Stops the bindings, disconnects all clients and waits for their messages to be sent.
+///
+/// When `restarting` is true, clients are asked to reconnect.
+async fn shutdown(
+    shared: &State,
+    bindings: Vec<(BindAddress, mpsc::Sender<Command>)>,
+    restarting: bool,
+) {
+    log::info!("Shutting down");
+    systemd::stopping();
+
+    // Binding tasks stop when their command channel is closed.
+    drop(bindings);
+
+    let sendqs = shared.shutdown(restarting).await;
+    let flushed = async {
+        while !sendqs.iter().all(|sendq| sendq.is_empty()) {
+            time::delay_for(Duration::from_millis(50)).await;
+        }
+    };
+    let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+    if time::timeout(timeout, flushed).await.is_err() {
+        log::warn!("Some messages could not be sent to clients before shutting down");
+    }
+}
+
 pub fn load_config_and_run(config_path: String) {
     let cfg = Config::from_file(&config_path).unwrap_or_else(|err| {
         log::error!("Failed to read {:?}: {}", config_path, err);
         process::exit(1);
     });
 
+    let inherited = inherited_listeners();
     let mut runtime = create_runtime(cfg.workers);
 
-    runtime.block_on(run(config_path, cfg));
+    runtime.block_on(run(config_path, cfg, inherited));
 }
 
-pub async fn run(config_path: String, cfg: Config) {
+pub async fn run(config_path: String, cfg: Config, mut inherited: Listeners) {
     #[cfg(unix)]
     let mut signals = unix::signal(unix::SignalKind::user_defined1()).unwrap_or_else(|err| {
         log::error!(
@@ -303,23 +679,70 @@ pub async fn run(config_path: String, cfg: Config) {
         process::exit(1);
     });
 
+    #[cfg(unix)]
+    let mut terminate = unix::signal(unix::SignalKind::terminate()).unwrap_or_else(|err| {
+        log::error!("Cannot listen for SIGTERM: {}", err);
+        process::exit(1);
+    });
+
+    #[cfg(unix)]
+    let mut interrupt = unix::signal(unix::SignalKind::interrupt()).unwrap_or_else(|err| {
+        log::error!("Cannot listen for SIGINT: {}", err);
+        process::exit(1);
+    });
+
+    #[cfg(unix)]
+    let mut restart_signals = unix::signal(unix::SignalKind::user_defined2()).unwrap_or_else(|err| {
+        log::error!("Cannot listen for signals to restart: {}", err);
+        process::exit(1);
+    });
+
     #[cfg(not(unix))]
     let signals = tokio::stream::pending();
 
+    #[cfg(not(unix))]
+    let restart_signals = tokio::stream::pending();
+
+    #[cfg(not(unix))]
+    let terminate = tokio::stream::pending();
+
+    #[cfg(not(unix))]
+    let interrupt = tokio::stream::pending();
+
     let (stop, mut failures) = mpsc::channel(8);
     let rehash = Arc::new(Notify::new());
+    let die = Arc::new(Notify::new());
+    let restart = Arc::new(Notify::new());
+
+    let shared = State::new(cfg.state, rehash.clone(), die.clone(), restart.clone()).await;
+    let throttle = Throttle::new(cfg.throttle);
+    let mut watched = watch_tls(&cfg.bindings);
+    let mut tls_watch = time::interval(Duration::from_secs(TLS_WATCH_INTERVAL_SECS));
+    let mut bindings = load_bindings(cfg.bindings, &shared, &throttle, &stop, &mut inherited);
+    if let Some(path) = cfg.control_socket {
+        tokio::spawn(admin::serve(path, shared.clone(), rehash.clone()));
+    }
+    if let Some(addr) = cfg.metrics_binding {
+        tokio::spawn(metrics::serve(addr, shared.clone()));
+    }
 
-    let shared = State::new(cfg.state, rehash.clone()).await;
-    let mut bindings = load_bindings(cfg.bindings, &shared, &stop);
+    // Inherited sockets that match no binding are closed.
+    drop(inherited);
+    systemd::ready();
+    update_bindings(&shared, &bindings, &watched).await;
 
-    loop {
+    let restarting = loop {
         tokio::select! {
             addr = failures.recv() => match addr {
-                Some(addr) => for i in 0..bindings.len() {
-                    if bindings[i].0 == addr {
-                        bindings.swap_remove(i);
-                        break;
+                Some(addr) => {
+                    for i in 0..bindings.len() {
+                        if bindings[i].0 == addr {
+                            bindings.swap_remove(i);
+                            break;
+                        }
                     }
+                    watched.retain(|w| w.address != addr);
+                    update_bindings(&shared, &bindings, &watched).await;
                 }
                 None => {
                     // `failures.recv()` returns `None` when all senders have been dropped, so
@@ -329,11 +752,41 @@ pub async fn run(config_path: String, cfg: Config) {
                 }
             },
             _ = rehash.notified() => {
-                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings).await;
+                do_rehash(
+                    config_path.clone(),
+                    &shared,
+                    &throttle,
+                    stop.clone(),
+                    &mut bindings,
+                    &mut watched,
+                )
+                .await;
+            },
+            _ = tls_watch.tick() => {
+                do_reload_tls(&mut watched, &mut bindings).await;
             },
             _ = signals.recv() => {
-                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings).await;
+                do_rehash(
+                    config_path.clone(),
+                    &shared,
+                    &throttle,
+                    stop.clone(),
+                    &mut bindings,
+                    &mut watched,
+                )
+                .await;
+            },
+            _ = die.notified() => break false,
+            _ = terminate.recv() => break false,
+            _ = interrupt.recv() => break false,
+            _ = restart.notified() => if do_restart(&mut bindings).await {
+                break true;
+            },
+            _ = restart_signals.recv() => if do_restart(&mut bindings).await {
+                break true;
             },
         }
-    }
+    };
+
+    shutdown(&shared, bindings, restarting).await;
 }
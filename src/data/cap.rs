@@ -136,6 +136,7 @@ caps! {
     AWAY_NOTIFY       "away-notify"        away_notify
     BATCH             "batch"              batch
     CAP_NOTIFY        "cap-notify"         cap_notify
+    CHGHOST           "chghost"            chghost
     ECHO_MESSAGE      "echo-message"       echo_message
     EXTENDED_JOIN     "extended-join"      extended_join
     INVITE_NOTIFY     "invite-notify"      invite_notify
@@ -147,6 +148,7 @@ caps! {
     USERHOST_IN_NAMES "userhost-in-names"  userhost_in_names
     |
     SASL "sasl" sasl
+    STS  "sts"  sts
 }
 
 impl Capabilities {
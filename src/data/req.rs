@@ -42,11 +42,41 @@ pub struct Kill<'a> {
     pub reason: &'a str,
 }
 #[derive(Clone, Copy, Debug)]
+pub struct ChgHost<'a> {
+    pub who: Nickname<'a>,
+    pub user: &'a str,
+    pub host: &'a str,
+}
+#[derive(Clone, Debug)]
+pub struct SaJoin<'a> {
+    pub who: Nickname<'a>,
+    pub channels: JoinList<'a>,
+}
+#[derive(Clone, Copy, Debug)]
+pub struct SaNick<'a> {
+    pub who: Nickname<'a>,
+    pub nick: Nickname<'a>,
+}
+#[derive(Clone, Copy, Debug)]
+pub struct SaPart<'a> {
+    pub who: Nickname<'a>,
+    pub part: Part<'a>,
+}
+#[derive(Clone, Copy, Debug)]
 pub struct Oper<'a> {
     pub name: &'a str,
     pub password: &'a str,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct WebIrc<'a> {
+    pub password: &'a str,
+    pub gateway: &'a str,
+    pub hostname: Option<HostName<'a>>,
+    pub ip: &'a str,
+    pub flags: &'a str,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct TopicSet<'a> {
     pub channel: ChannelName<'a>,
@@ -62,6 +92,9 @@ pub struct User<'a> {
 pub struct ModeUserSet<'a> {
     pub user: Nickname<'a>,
     pub modes: modes::User<'a>,
+
+    /// The parameter of `+s`, see `snomask`.
+    pub snomask: Option<&'a str>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -114,6 +147,7 @@ pub enum Request<'a> {
     LUsers,
     Motd,
     Time,
+    Stats(&'a str),
     Version,
     WhoChannel(WhoChannel<'a>),
     WhoMask(WhoMask<'a>),
@@ -122,9 +156,16 @@ pub enum Request<'a> {
     WhoIs(Nickname<'a>),
 
     // IRCop restricted requests.
+    ChgHost(ChgHost<'a>),
+    Die,
     Kill(Kill<'a>),
     Oper(Oper<'a>),
     Rehash,
+    Restart,
+    SaJoin(SaJoin<'a>),
+    SaMode(ModeChannelSet<'a>),
+    SaNick(SaNick<'a>),
+    SaPart(SaPart<'a>),
 
     // Requests about channel info.
     List(List<'a, ChannelName<'a>>),
@@ -145,6 +186,7 @@ pub enum Request<'a> {
     Pong(&'a str),
     Quit(Option<&'a str>),
     User(User<'a>),
+    WebIrc(WebIrc<'a>),
 
     // Client info related requests.
     Away(Option<&'a str>),
@@ -181,6 +223,7 @@ impl<'a> Request<'a> {
             Command::Info => Self::Info,
             Command::LUsers => Self::LUsers,
             Command::Motd => Self::Motd,
+            Command::Stats => Self::Stats(msg.params[0]),
             Command::Time => Self::Time,
             Command::Version => Self::Version,
             Command::Who => {
@@ -202,6 +245,13 @@ impl<'a> Request<'a> {
                 Self::WhoIs(mask)
             }
 
+            Command::ChgHost => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let user = msg.params[1];
+                let host = msg.params[2];
+                Self::ChgHost(ChgHost { who, user, host })
+            }
+            Command::Die => Self::Die,
             Command::Kill => {
                 let who = Nickname::try_from(msg.params[0])?;
                 let reason = msg.params[1];
@@ -213,6 +263,34 @@ impl<'a> Request<'a> {
                 Self::Oper(Oper { name, password })
             }
             Command::Rehash => Self::Rehash,
+            Command::Restart => Self::Restart,
+            Command::SaJoin => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let channels = JoinList::new(msg.params[1], msg.params[2]);
+                Self::SaJoin(SaJoin { who, channels })
+            }
+            Command::SaMode => {
+                let channel = ChannelName::try_from(msg.params[0])?;
+                let n = msg.num_params;
+                let modes = modes::Channel::new(msg.params[1], &msg.params[2..n]);
+                Self::SaMode(ModeChannelSet { channel, modes })
+            }
+            Command::SaNick => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let nick = Nickname::try_from(msg.params[1])
+                    .map_err(|_| Error::ErroneousNickname(msg.params[1]))?;
+                Self::SaNick(SaNick { who, nick })
+            }
+            Command::SaPart => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let from = List::new(msg.params[1], ',');
+                let reason = if msg.params[2].is_empty() {
+                    None
+                } else {
+                    Some(msg.params[2])
+                };
+                Self::SaPart(SaPart { who, part: Part { from, reason } })
+            }
 
             Command::List => {
                 let channel_names = msg.params[0];
@@ -284,6 +362,20 @@ impl<'a> Request<'a> {
                 let realname = msg.params[3];
                 Self::User(User { username, realname })
             }
+            Command::WebIrc => {
+                let password = msg.params[0];
+                let gateway = msg.params[1];
+                let hostname = HostName::try_from(msg.params[2]).ok();
+                let ip = msg.params[3];
+                let flags = msg.params[4];
+                Self::WebIrc(WebIrc {
+                    password,
+                    gateway,
+                    hostname,
+                    ip,
+                    flags,
+                })
+            }
 
             Command::Away => {
                 let reason = if msg.params[0].is_empty() {
@@ -308,7 +400,8 @@ impl<'a> Request<'a> {
                         Self::ModeUserGet(user)
                     } else {
                         let modes = modes::User::new(msg.params[1]);
-                        Self::ModeUserSet(ModeUserSet { user, modes })
+                        let snomask = if n == 2 { None } else { Some(msg.params[2]) };
+                        Self::ModeUserSet(ModeUserSet { user, modes, snomask })
                     }
                 }
             }
@@ -405,6 +498,7 @@ impl<'a> Request<'a> {
             Self::Info => 3,
             Self::LUsers => 3,
             Self::Motd => 3,
+            Self::Stats(_) => 8,
             Self::Time => 2,
             Self::Version => 2,
             Self::WhoChannel(_) => 5,
@@ -414,9 +508,16 @@ impl<'a> Request<'a> {
             Self::WhoIs(_) => 4,
 
             // IRCop restricted requests.
+            Self::ChgHost(_) => 16,
+            Self::Die => 16,
             Self::Kill(_) => 16,
             Self::Oper(_) => 16,
             Self::Rehash => 16,
+            Self::Restart => 16,
+            Self::SaJoin(_) => 16,
+            Self::SaMode(_) => 16,
+            Self::SaNick(_) => 16,
+            Self::SaPart(_) => 16,
 
             // Requests about channel info.
             Self::List(_) => 4,
@@ -437,6 +538,7 @@ impl<'a> Request<'a> {
             Self::Pong(_) => 2,
             Self::Quit(_) => 2,
             Self::User(_) => 2,
+            Self::WebIrc(_) => 4,
 
             // Client info related requests.
             Self::Away(_) => 8,
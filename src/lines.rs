@@ -23,6 +23,22 @@ where
 
 pub const REGISTRATION_TIMEOUT: &str = "Senpai is such a slowpoke... baka";
 
+pub const PING_TIMEOUT: &str = "Senpai stopped answering... (ping timeout)";
+
+pub const SENDQ_EXCEEDED: &str = "Senpai can't keep up with all these messages! (SendQ exceeded)";
+
+pub const NO_CLASS: &str = "Senpai isn't allowed to connect here...";
+
+pub const CLASS_FULL: &str = "It's crowded in here senpai, please come back later!";
+
+pub const TOO_MANY_CONNECTIONS: &str = "Senpai, you have too many connections from there!";
+
+pub const WEBIRC_REFUSED: &str = "Senpai's gateway isn't allowed here...";
+
+pub const WEBIRC_TOO_LATE: &str = "Senpai's gateway must send WEBIRC before registering!";
+
+pub const TOO_MANY_FAILURES: &str = "Senpai, stop guessing passwords! (too many failed attempts)";
+
 //
 // IRC replies
 //
@@ -59,12 +75,16 @@ pub const END_OF_MOTD: &str = "End of MOTD";
 
 pub const END_OF_NAMES: &str = "End of names";
 
+pub const END_OF_STATS: &str = "End of STATS report";
+
 pub const END_OF_WHO: &str = "End of WHO list";
 
 pub const END_OF_WHOIS: &str = "End of WHOIS list";
 
 pub const ERRONEOUS_NICKNAME: &str = "Meh, this is obviously a bad nickname...";
 
+pub const HOST_HIDDEN: &str = "is now your displayed host";
+
 pub const INPUT_TOO_LONG: &str =
     "Please wait senpai, that's too big!  If only there was one message at a time...";
 
@@ -92,10 +112,28 @@ pub const NOT_REGISTERED: &str = "You must register first!";
 
 pub const PASSWORD_MISMATCH: &str = "Nope! Wrong password";
 
+pub const AUTH_LOCKED: &str = "Too many wrong passwords, senpai should wait a bit before trying again";
+
 pub const PART_ALL: &str = "Baka!";
 
 pub const REHASHING: &str = "Oh~~!  Onwards to reload the configuration!";
 
+pub const SNOMASK: &str = "Senpai will be told about these!";
+
+pub const STATS_HELP: &[&str] = &[
+    "u - uptime",
+    "m - number and size of messages by command",
+    "l - connections, with their queue and traffic",
+    "L - same as l, with IP addresses instead of hosts",
+    "o - operators",
+    "k - bans",
+    "d - host bans",
+    "P - bindings",
+    "? - this help",
+];
+
+pub const RESTARTING: &str = "Be right back, senpai!  ellidri is restarting~";
+
 pub const UNKNOWN_COMMAND: &str = "Hnn... What did you just say?";
 
 pub const UNKNOWN_MODE: &str = "This letter right here... what does it mean?";
@@ -110,6 +148,10 @@ pub const YOURE_OPER: &str = "You are now a BIG senpai!";
 
 pub const WHOIS_IDLE: &str = "Seconds since last activity, registration time";
 
+pub const WHOIS_CERTFP: &str = "has client certificate fingerprint";
+
+pub const WHOIS_SECURE: &str = "is using a secure connection";
+
 //
 // Welcome messages
 //
@@ -157,6 +199,20 @@ macro_rules! lines_motd_start {
     };
 }
 
+#[macro_export]
+macro_rules! lines_stats_uptime {
+    ( $secs:expr ) => {{
+        let secs = $secs;
+        format_args!(
+            "Server Up {} days {}:{:02}:{:02}",
+            secs / 86400,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }};
+}
+
 #[macro_export]
 macro_rules! lines_welcome {
     ( $name:expr ) => {
@@ -192,3 +248,11 @@ macro_rules! lines_logged_in {
 //
 
 pub const INVALID_REALNAME: &str = "Meh, this is obviously a bad realname...";
+
+//
+// Chghost
+//
+
+pub const CHANGING_HOST: &str = "Changing host";
+
+pub const INVALID_HOST: &str = "Meh, this is obviously a bad user or host...";
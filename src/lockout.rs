@@ -0,0 +1,173 @@
+//! Brute-force protection.
+//!
+//! `Lockout` keeps track of failed authentication attempts (OPER and PASS) per IP address, or per
+//! subnet for IPv6, and per operator name.  After each failure, further attempts are refused for a
+//! delay that doubles with each failure, and after `lockout_failures` failures they are refused for
+//! `lockout_duration`.  Refused attempts are not checked, so that they cost nothing.
+//!
+//! SASL attempts are not counted yet, since AUTHENTICATE is not implemented (`cmd_authenticate`).
+
+use crate::config;
+use crate::util::Cidr;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// What failed attempts are counted for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Ip(Cidr),
+    Oper(String),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(subnet) => write!(f, "address {}", subnet),
+            Self::Oper(name) => write!(f, "operator name {}", name),
+        }
+    }
+}
+
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    until: Instant,
+}
+
+pub struct Lockout {
+    config: config::AuthLockout,
+    entries: HashMap<Key, Entry>,
+    last_cleanup: Instant,
+}
+
+impl Lockout {
+    pub fn new(config: config::AuthLockout) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Applies new settings.  Failed attempts are kept.
+    pub fn rehash(&mut self, config: config::AuthLockout) {
+        self.config = config;
+    }
+
+    /// The key for attempts from `ip`.  IPv6 addresses are grouped by subnet, since a client
+    /// usually has a whole one.
+    pub fn ip_key(&self, ip: IpAddr) -> Key {
+        match ip {
+            IpAddr::V4(_) => Key::Ip(Cidr::of(ip, 32)),
+            IpAddr::V6(_) => Key::Ip(Cidr::of(ip, self.config.ipv6_prefix)),
+        }
+    }
+
+    /// The number of failed attempts after which clients are disconnected, 0 for unlimited.
+    pub fn max_failures(&self) -> u32 {
+        self.config.max_failures
+    }
+
+    /// Returns the time left before an attempt is allowed for all the given keys, or `None` if it
+    /// is allowed now.
+    pub fn check(&self, keys: &[Key], now: Instant) -> Option<Duration> {
+        keys.iter()
+            .filter_map(|key| self.entries.get(key))
+            .map(|entry| entry.until.saturating_duration_since(now))
+            .filter(|left| *left != Duration::from_secs(0))
+            .max()
+    }
+
+    /// Records a failed attempt for the given keys.
+    ///
+    /// Returns the keys that have just been locked out.
+    pub fn failed(&mut self, keys: &[Key], now: Instant) -> Vec<Key> {
+        let forget_after = Duration::from_millis(self.config.lockout_duration);
+        if forget_after <= now.duration_since(self.last_cleanup) {
+            self.entries.retain(|_, entry| {
+                now.duration_since(entry.last_failure) < forget_after || now < entry.until
+            });
+            self.last_cleanup = now;
+        }
+
+        let mut locked = Vec::new();
+        for key in keys {
+            let entry = self.entries.entry(key.clone()).or_insert(Entry {
+                failures: 0,
+                last_failure: now,
+                until: now,
+            });
+            if forget_after <= now.duration_since(entry.last_failure) {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            let delay = if self.config.lockout_failures <= entry.failures {
+                if self.config.lockout_failures == entry.failures {
+                    locked.push(key.clone());
+                }
+                forget_after
+            } else {
+                let factor = 1u64.checked_shl(entry.failures - 1).unwrap_or(u64::MAX);
+                Duration::from_millis(self.config.delay.saturating_mul(factor)).min(forget_after)
+            };
+            entry.until = now + delay;
+        }
+        locked
+    }
+
+    /// Forgets the failed attempts for the given key.
+    pub fn succeeded(&mut self, key: &Key) {
+        self.entries.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout() {
+        let config = config::AuthLockout {
+            max_failures: 3,
+            delay: 100,
+            lockout_failures: 3,
+            lockout_duration: 10_000,
+            ipv6_prefix: 64,
+        };
+        let mut lockout = Lockout::new(config);
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let ip = [lockout.ip_key("192.0.2.1".parse().unwrap())];
+        let oper = [Key::Oper(String::from("root"))];
+        let keys = [ip[0].clone(), oper[0].clone()];
+
+        assert_eq!(lockout.check(&keys, start), None);
+        assert!(lockout.failed(&keys, start).is_empty());
+        assert_eq!(lockout.check(&keys, start), Some(ms(100)));
+        assert_eq!(lockout.check(&keys, start + ms(100)), None);
+
+        assert!(lockout.failed(&ip, start + ms(100)).is_empty());
+        assert_eq!(lockout.check(&oper, start + ms(100)), None);
+        assert_eq!(lockout.check(&keys, start + ms(100)), Some(ms(200)));
+
+        assert_eq!(lockout.failed(&keys, start + ms(300)), ip.to_vec());
+        assert_eq!(lockout.check(&ip, start + ms(300)), Some(ms(10_000)));
+        assert_eq!(lockout.check(&oper, start + ms(300)), Some(ms(200)));
+
+        lockout.succeeded(&oper[0]);
+        assert_eq!(lockout.check(&oper, start + ms(300)), None);
+        assert_eq!(lockout.check(&ip, start + ms(10_300)), None);
+
+        assert!(lockout.failed(&ip, start + ms(20_300)).is_empty());
+        assert_eq!(lockout.check(&ip, start + ms(20_300)), Some(ms(100)));
+
+        let ipv6 = lockout.ip_key("2001:db8::1".parse().unwrap());
+        assert_eq!(ipv6, lockout.ip_key("2001:db8::2:1".parse().unwrap()));
+        assert_ne!(ipv6, lockout.ip_key("2001:db8:0:1::1".parse().unwrap()));
+        assert_ne!(ip[0], lockout.ip_key("192.0.2.2".parse().unwrap()));
+    }
+} // mod tests
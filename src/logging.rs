@@ -0,0 +1,92 @@
+//! Log output.
+//!
+//! Logs are written to stderr, either as text (the default) or as JSON objects, one per line, when
+//! `ELLIDRI_LOG_FORMAT` is set to "json".  The level is set by `ELLIDRI_LOG` (see the
+//! documentation of `env_logger`), and colors by `ELLIDRI_LOG_STYLE`.
+//!
+//! Records can carry structured fields, such as the identifier of the client ("client"), the
+//! address of the peer ("peer") and the command being handled ("command"):
+//!
+//! ```ignore
+//! log::debug!(client = id, command = "JOIN"; "{}: {:?}", id, req);
+//! ```
+//!
+//! These fields are only written in JSON logs, as members of the object.
+
+use log::kv::{self, VisitSource};
+use serde_json::{Map, Value};
+use std::env;
+use std::io::Write as _;
+
+/// Adds the key-values of a record to a JSON object.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_i64() {
+            Value::from(n)
+        } else if let Some(b) = value.to_bool() {
+            Value::from(b)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Returns the given record as a JSON object.
+fn json_record(r: &log::Record<'_>) -> Value {
+    let mut obj = Map::new();
+    obj.insert("time".to_owned(), Value::from(crate::util::time_precise()));
+    obj.insert("level".to_owned(), Value::from(r.level().as_str()));
+    obj.insert("target".to_owned(), Value::from(r.target()));
+    obj.insert("message".to_owned(), Value::from(r.args().to_string()));
+    let _ = r.key_values().visit(&mut Fields(&mut obj));
+    Value::Object(obj)
+}
+
+/// Initializes the logger from the environment.
+pub fn init() {
+    let log_settings = env_logger::Env::new()
+        .filter_or("ELLIDRI_LOG", "ellidri=debug")
+        .write_style("ELLIDRI_LOG_STYLE");
+    let mut builder = env_logger::Builder::from_env(log_settings);
+    if env::var("ELLIDRI_LOG_FORMAT").as_deref() == Ok("json") {
+        builder.format(|buf, r| writeln!(buf, "{}", json_record(r)));
+    } else {
+        builder.format(|buf, r| writeln!(buf, "[{:<5} {}] {}", r.level(), r.target(), r.args()));
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let kvs: [(&str, kv::Value<'_>); 3] = [
+            ("client", kv::Value::from(3usize)),
+            ("peer", kv::Value::from("192.0.2.1:4242")),
+            ("command", kv::Value::from("JOIN")),
+        ];
+        let record = log::Record::builder()
+            .args(format_args!("3: Join"))
+            .level(log::Level::Debug)
+            .target("ellidri::state")
+            .key_values(&kvs)
+            .build();
+        let obj = json_record(&record);
+
+        assert_eq!(obj["level"], "DEBUG");
+        assert_eq!(obj["target"], "ellidri::state");
+        assert_eq!(obj["message"], "3: Join");
+        assert_eq!(obj["client"], 3);
+        assert_eq!(obj["peer"], "192.0.2.1:4242");
+        assert_eq!(obj["command"], "JOIN");
+        assert!(obj["time"].is_string());
+    }
+} // mod tests
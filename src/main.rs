@@ -14,8 +14,11 @@ use crate::channel::Channel;
 use crate::client::Client;
 use crate::config::Config;
 use crate::state::State;
+use std::path::PathBuf;
 use std::{env, process};
 
+mod admin;
+mod audit;
 mod channel;
 mod client;
 mod config;
@@ -23,8 +26,16 @@ mod control;
 mod data;
 #[macro_use]
 mod lines;
+mod lockout;
+mod logging;
+mod metrics;
 mod net;
+mod password;
+mod proxy;
+mod snomask;
 mod state;
+mod systemd;
+mod throttle;
 mod util;
 
 pub fn main() {
@@ -32,38 +43,95 @@ pub fn main() {
         env::set_var("RUST_BACKTRACE", "1");
     }
 
-    let log_settings = env_logger::Env::new()
-        .filter_or("ELLIDRI_LOG", "ellidri=debug")
-        .write_style("ELLIDRI_LOG_STYLE");
-    env_logger::Builder::from_env(log_settings)
-        .format(|buf, r| {
-            use std::io::Write;
-            writeln!(buf, "[{:<5} {}] {}", r.level(), r.target(), r.args())
-        })
-        .init();
-
-    let config_path = parse_args();
-    control::load_config_and_run(config_path);
+    logging::init();
+
+    match parse_args() {
+        Mode::Run(config_path) => control::load_config_and_run(config_path),
+        Mode::Ctl(socket, req) => {
+            let ok = admin::ctl(&socket, &req);
+            process::exit(if ok { 0 } else { 1 });
+        }
+        Mode::MkPasswd => mkpasswd(),
+    }
+}
+
+/// What ellidri has been asked to do.
+enum Mode {
+    /// Run the server with the given configuration file.
+    Run(String),
+
+    /// Send a request to the admin socket at the given path.
+    Ctl(PathBuf, admin::Request),
+
+    /// Print the hash of a password read from stdin.
+    MkPasswd,
 }
 
-fn parse_args() -> String {
+fn usage(program: &str) {
+    eprintln!("Usage: {} CONFIG_FILE", program);
+    eprintln!("       {} ctl [-s SOCKET] ACTION [ARGS...]", program);
+    eprintln!("       {} mkpasswd", program);
+}
+
+fn parse_args() -> Mode {
     let mut args = env::args();
 
     let program = args.next().unwrap();
 
     let config_path = args.next().unwrap_or_else(|| {
-        eprintln!("Usage: {} CONFIG_FILE", program);
+        usage(&program);
         process::exit(1);
     });
 
     if config_path == "-h" || config_path == "--help" {
         eprintln!("ellidri {}", env!("CARGO_PKG_VERSION"));
-        eprintln!("Usage: {} CONFIG_FILE", program);
+        usage(&program);
         process::exit(1);
     } else if config_path == "-v" || config_path == "--version" {
         eprintln!("ellidri {}", env!("CARGO_PKG_VERSION"));
         process::exit(1);
+    } else if config_path == "ctl" {
+        return parse_ctl_args(&program, args.collect());
+    } else if config_path == "mkpasswd" {
+        return Mode::MkPasswd;
     }
 
-    config_path
+    Mode::Run(config_path)
+}
+
+fn parse_ctl_args(program: &str, mut args: Vec<String>) -> Mode {
+    let mut socket = PathBuf::from(admin::DEFAULT_SOCKET);
+    if args.first().map(String::as_str) == Some("-s") && 2 <= args.len() {
+        socket = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+
+    match admin::Request::from_args(&args) {
+        Some(req) => Mode::Ctl(socket, req),
+        None => {
+            usage(program);
+            eprintln!("\n{}", admin::CTL_USAGE);
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads a password from stdin and prints its hash, to be put in the configuration file.
+fn mkpasswd() {
+    use std::io::{self, BufRead as _, IsTerminal as _};
+
+    if io::stdin().is_terminal() {
+        eprint!("Password: ");
+    }
+    let mut line = String::new();
+    if let Err(err) = io::stdin().lock().read_line(&mut line) {
+        eprintln!("Failed to read the password: {}", err);
+        process::exit(1);
+    }
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        eprintln!("The password is empty");
+        process::exit(1);
+    }
+    println!("{}", password::hash(password));
 }
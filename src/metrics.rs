@@ -0,0 +1,249 @@
+//! Prometheus metrics.
+//!
+//! Counters are kept in `Metrics`, which is owned by the shared state and updated under its lock
+//! by `StateInner::handle_message` and by the connection tasks of `net`.  Gauges are computed from
+//! the state when metrics are scraped.
+//!
+//! When `metrics_binding` is set, `serve` answers HTTP requests on `/metrics` with all metrics in
+//! the Prometheus text format.
+
+use crate::State;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::{net, time};
+
+/// Upper bounds of the buckets of the `handle_message` latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05];
+
+/// Maximum size of an HTTP request.
+const MAX_REQUEST_LEN: usize = 4096;
+
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+/// Values computed from the state when metrics are scraped.
+pub struct Gauges<'a> {
+    pub clients: usize,
+    pub registered: usize,
+    pub channels: usize,
+
+    /// Number of clients connected to each binding.
+    pub clients_per_binding: HashMap<String, usize>,
+
+    /// Bytes waiting in the message queues of the clients, in total and for the longest one.
+    pub sendq_bytes: usize,
+    pub sendq_max_bytes: usize,
+
+    /// The counters.
+    pub metrics: &'a Metrics,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of accepted connections, by binding.
+    connections: HashMap<String, u64>,
+
+    /// Number of messages handled and their size in bytes, by command.
+    messages: HashMap<&'static str, (u64, u64)>,
+
+    /// Number of times a client has been slowed down by its rate limit.
+    rate_limited: u64,
+
+    tls_handshake_failures: u64,
+
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    latency_count: u64,
+}
+
+impl Metrics {
+    pub fn connection_accepted(&mut self, binding: String) {
+        *self.connections.entry(binding).or_insert(0) += 1;
+    }
+
+    /// Records a message of `len` bytes handled by `StateInner::handle_message`, and the time it
+    /// took.
+    pub fn message_handled(&mut self, command: &'static str, len: usize, elapsed: Duration) {
+        let (count, bytes) = self.messages.entry(command).or_insert((0, 0));
+        *count += 1;
+        *bytes += len as u64;
+
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.latency_sum += secs;
+        self.latency_count += 1;
+    }
+
+    /// Returns the number of messages handled and their size in bytes, by command.
+    pub fn messages(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + '_ {
+        self.messages.iter().map(|(command, (count, bytes))| (*command, *count, *bytes))
+    }
+
+    pub fn rate_limited(&mut self) {
+        self.rate_limited += 1;
+    }
+
+    pub fn tls_handshake_failed(&mut self) {
+        self.tls_handshake_failures += 1;
+    }
+}
+
+/// Writes the HELP and TYPE lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Returns all metrics in the Prometheus text format.
+pub fn render(g: &Gauges<'_>) -> String {
+    let m = g.metrics;
+    let mut out = String::new();
+
+    header(&mut out, "ellidri_clients", "gauge", "Connected clients.");
+    let _ = writeln!(out, "ellidri_clients {}", g.clients);
+    header(&mut out, "ellidri_registered_clients", "gauge", "Registered clients.");
+    let _ = writeln!(out, "ellidri_registered_clients {}", g.registered);
+    header(&mut out, "ellidri_channels", "gauge", "Channels.");
+    let _ = writeln!(out, "ellidri_channels {}", g.channels);
+
+    header(&mut out, "ellidri_binding_clients", "gauge", "Connected clients by binding.");
+    for (binding, n) in &g.clients_per_binding {
+        let _ = writeln!(out, "ellidri_binding_clients{{binding=\"{}\"}} {}", label(binding), n);
+    }
+    header(&mut out, "ellidri_connections_total", "counter", "Accepted connections by binding.");
+    for (binding, n) in &m.connections {
+        let _ = writeln!(out, "ellidri_connections_total{{binding=\"{}\"}} {}", label(binding), n);
+    }
+
+    header(&mut out, "ellidri_sendq_bytes", "gauge", "Bytes waiting in message queues.");
+    let _ = writeln!(out, "ellidri_sendq_bytes {}", g.sendq_bytes);
+    header(&mut out, "ellidri_sendq_max_bytes", "gauge", "Bytes waiting in the longest message queue.");
+    let _ = writeln!(out, "ellidri_sendq_max_bytes {}", g.sendq_max_bytes);
+
+    header(&mut out, "ellidri_messages_total", "counter", "Messages handled by command.");
+    for (command, n, _) in m.messages() {
+        let _ = writeln!(out, "ellidri_messages_total{{command=\"{}\"}} {}", label(command), n);
+    }
+    header(&mut out, "ellidri_message_bytes_total", "counter", "Size of the messages handled by command.");
+    for (command, _, bytes) in m.messages() {
+        let _ = writeln!(out, "ellidri_message_bytes_total{{command=\"{}\"}} {}", label(command), bytes);
+    }
+    header(&mut out, "ellidri_rate_limited_total", "counter", "Times clients have been slowed down by rate limits.");
+    let _ = writeln!(out, "ellidri_rate_limited_total {}", m.rate_limited);
+    header(&mut out, "ellidri_tls_handshake_failures_total", "counter", "Failed or timed out TLS handshakes.");
+    let _ = writeln!(out, "ellidri_tls_handshake_failures_total {}", m.tls_handshake_failures);
+
+    let name = "ellidri_handle_message_seconds";
+    header(&mut out, name, "histogram", "Time spent handling a message.");
+    for (bound, n) in LATENCY_BUCKETS.iter().zip(m.latency_buckets.iter()) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, n);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, m.latency_count);
+    let _ = writeln!(out, "{}_sum {}", name, m.latency_sum);
+    let _ = writeln!(out, "{}_count {}", name, m.latency_count);
+
+    out
+}
+
+/// Reads an HTTP request and answers it.
+async fn handle_conn(mut conn: net::TcpStream, shared: State) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 512];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 || MAX_REQUEST_LEN < buf.len() + n {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = buf.split(|&b| b == b'\r').next().unwrap_or(&[]);
+    let mut words = request_line.split(|&b| b == b' ');
+    let (status, body) = match (words.next(), words.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", shared.metrics().await),
+        (Some(b"GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", String::from("Method not allowed\n")),
+    };
+
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown(std::net::Shutdown::Write)
+}
+
+/// Listens on `addr` and serves metrics over HTTP.
+pub async fn serve(addr: SocketAddr, shared: State) {
+    let bind = || -> std::io::Result<_> {
+        let ln = std::net::TcpListener::bind(addr)?;
+        ln.set_nonblocking(true)?;
+        net::TcpListener::from_std(ln)
+    };
+    let mut ln = match bind() {
+        Ok(ln) => ln,
+        Err(err) => {
+            log::error!("Metrics binding {} failed to come online: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Metrics binding {} online", addr);
+
+    loop {
+        match ln.accept().await {
+            Ok((conn, peer_addr)) => {
+                let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+                let conn = time::timeout(timeout, handle_conn(conn, shared.clone()));
+                tokio::spawn(async move {
+                    if let Ok(Err(err)) = conn.await {
+                        log::debug!("Metrics request from {} failed: {}", peer_addr, err);
+                    }
+                });
+            }
+            Err(err) => log::warn!("Metrics binding {} failed to accept a connection: {}", addr, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut metrics = Metrics::default();
+        metrics.message_handled("PING", 8, Duration::from_micros(3));
+        metrics.message_handled("PRIVMSG", 20, Duration::from_micros(700));
+        metrics.message_handled("PRIVMSG", 30, Duration::from_secs(1));
+
+        assert_eq!(metrics.latency_buckets, [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(metrics.latency_count, 3);
+        assert_eq!(metrics.messages["PRIVMSG"], (2, 50));
+
+        let gauges = Gauges {
+            clients: 0,
+            registered: 0,
+            channels: 0,
+            clients_per_binding: HashMap::new(),
+            sendq_bytes: 0,
+            sendq_max_bytes: 0,
+            metrics: &metrics,
+        };
+        let text = render(&gauges);
+        assert!(text.contains("ellidri_messages_total{command=\"PING\"} 1\n"));
+        assert!(text.contains("ellidri_handle_message_seconds_bucket{le=\"+Inf\"} 3\n"));
+    }
+} // mod tests
@@ -1,4 +1,8 @@
-use crate::{control, lines, State};
+use crate::client::{Peer, SendQ};
+use crate::throttle::{self, Throttle, Throttled};
+use crate::util::{self, Cidr};
+use crate::config::BindAddress;
+use crate::{config, control, lines, proxy, snomask, State};
 use ellidri_reader::IrcReader;
 use ellidri_tokens::Message;
 use std::collections::HashMap;
@@ -10,46 +14,107 @@ use std::{fs, str};
 use tokio::sync::mpsc;
 use tokio::{io, net, sync, time};
 use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientCertVerified, ClientCertVerifier, ClientHello, DistinguishedNames,
+    ResolvesServerCert, ServerConfig, Session as _, TLSError,
+};
+use tokio_rustls::{webpki, TlsAcceptor};
 
 const KEEPALIVE_SECS: u64 = 75;
 const TLS_TIMEOUT_SECS: u64 = 30;
+const PROXY_TIMEOUT_SECS: u64 = 30;
 
 /// `TlsAcceptor` cache, to avoid reading the same files several times.
 #[derive(Default)]
 pub struct TlsIdentityStore {
-    acceptors: HashMap<PathBuf, Arc<TlsAcceptor>>,
+    acceptors: HashMap<config::Tls, Arc<TlsAcceptor>>,
 }
 
 impl TlsIdentityStore {
-    /// Retrieves the acceptor at `path`, or get it from the cache if it has already been built.
-    pub fn acceptor<P1, P2>(
+    /// Retrieves the acceptor for `tls`, or get it from the cache if it has already been built.
+    pub fn acceptor(
         &mut self,
-        cert: P1,
-        key: P2,
-    ) -> Result<Arc<TlsAcceptor>, Box<dyn Error + 'static>>
-    where
-        P1: AsRef<Path> + Into<PathBuf>,
-        P2: AsRef<Path> + Into<PathBuf>,
-    {
-        if let Some(acceptor) = self.acceptors.get(cert.as_ref()) {
+        tls: &config::Tls,
+    ) -> Result<Arc<TlsAcceptor>, Box<dyn Error + 'static>> {
+        if let Some(acceptor) = self.acceptors.get(tls) {
             Ok(acceptor.clone())
         } else {
-            let acceptor = Arc::new(build_acceptor(cert.as_ref(), key.as_ref())?);
-            self.acceptors.insert(cert.into(), acceptor.clone());
+            let acceptor = Arc::new(build_acceptor(tls)?);
+            self.acceptors.insert(tls.clone(), acceptor.clone());
             Ok(acceptor)
         }
     }
 }
 
-/// Read the file at `p`, parse the identity and builds a `TlsAcceptor` object.
-fn build_acceptor(
+/// Chooses the certificate to send to the client from the domain it asks for (SNI).
+struct SniResolver {
+    domains: HashMap<String, CertifiedKey>,
+    default: CertifiedKey,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        let certified_key = client_hello.server_name().and_then(|name| {
+            let name: &str = name.into();
+            self.domains.get(&name.to_ascii_lowercase())
+        });
+        Some(certified_key.unwrap_or(&self.default).clone())
+    }
+}
+
+/// Accepts any client certificate, including self-signed ones, so that clients can be identified
+/// by its fingerprint.
+///
+/// The TLS handshake still checks that clients own the private key of their certificate.
+struct AnyClientCert {
+    mandatory: bool,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(self.mandatory)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _presented_certs: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Read the files of `tls`, parse the identities and builds a `TlsAcceptor` object.
+fn build_acceptor(tls: &config::Tls) -> Result<TlsAcceptor, Box<dyn Error + 'static>> {
+    let verifier = AnyClientCert {
+        mandatory: tls.require_certificates,
+    };
+    let mut config = ServerConfig::new(Arc::new(verifier));
+
+    let default = load_certified_key(&tls.certificate, &tls.key)?;
+    let mut domains = HashMap::with_capacity(tls.sni.len());
+    for sni in &tls.sni {
+        let certified_key = load_certified_key(&sni.certificate, &sni.key)?;
+        domains.insert(sni.domain.to_ascii_lowercase(), certified_key);
+    }
+    config.cert_resolver = Arc::new(SniResolver { domains, default });
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Read the given files and parse the certificate chain and its private key.
+fn load_certified_key(
     certfile: &Path,
     keyfile: &Path,
-) -> Result<TlsAcceptor, Box<dyn Error + 'static>> {
-    let mut config = ServerConfig::new(NoClientAuth::new());
-
+) -> Result<CertifiedKey, Box<dyn Error + 'static>> {
     log::info!("Loading TLS certificate from {:?}", certfile.display());
     let cert = fs::read(certfile).map_err(|err| {
         log::error!("Failed to read {:?}: {}", certfile.display(), err);
@@ -76,8 +141,13 @@ fn build_acceptor(
         }
         keys.remove(0)
     };
+    let key = sign::any_supported_type(&key).map_err(|_| {
+        log::error!("Unsupported key type in {:?}", keyfile.display());
+        ""
+    })?;
 
-    config.set_single_cert(cert, key).map_err(|err| {
+    let certified_key = CertifiedKey::new(cert, Arc::new(key));
+    certified_key.cross_check_end_entity_cert(None).map_err(|err| {
         log::error!(
             "Failed to associate {:?} with {:?}: {}",
             certfile.display(),
@@ -87,18 +157,160 @@ fn build_acceptor(
         err
     })?;
 
-    Ok(TlsAcceptor::from(Arc::new(config)))
+    Ok(certified_key)
+}
+
+/// A listening socket, as given by the standard library.
+pub enum StdListener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl StdListener {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(ln) => ln.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(ln) => ln.try_clone().map(Self::Unix),
+        }
+    }
+
+    /// The address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<BindAddress> {
+        match self {
+            Self::Tcp(ln) => ln.local_addr().map(BindAddress::Tcp),
+            #[cfg(unix)]
+            Self::Unix(ln) => {
+                let addr = ln.local_addr()?;
+                let path = addr.as_pathname().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unnamed socket"))?;
+                Ok(BindAddress::Unix(path.to_owned()))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<StdListener> for std::os::unix::io::OwnedFd {
+    fn from(val: StdListener) -> Self {
+        match val {
+            StdListener::Tcp(ln) => ln.into(),
+            StdListener::Unix(ln) => ln.into(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(net::UnixListener),
+}
+
+/// An accepted connection, with the address of the client for TCP connections.
+enum Conn {
+    Tcp(net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(net::UnixStream),
+}
+
+impl Listener {
+    async fn accept(&mut self) -> io::Result<Conn> {
+        match self {
+            Self::Tcp(ln) => ln.accept().await.map(|(conn, addr)| Conn::Tcp(conn, addr)),
+            #[cfg(unix)]
+            Self::Unix(ln) => ln.accept().await.map(|(conn, _)| Conn::Unix(conn)),
+        }
+    }
+}
+
+/// Binds a Unix socket on `path` and sets the permissions of the socket file to `mode`.
+///
+/// A file left by a previous process on `path` is removed first, provided it is a socket that no
+/// process listens on anymore.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process listens on this socket"));
+            }
+            fs::remove_file(path)?;
+        }
+    }
+    let ln = std::os::unix::net::UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(ln)
+}
+
+/// Removes the file of a Unix socket when dropped, so also when the runtime shuts down.
+struct SocketFile(Option<PathBuf>);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            if let Err(err) = fs::remove_file(&path) {
+                log::warn!("Failed to remove {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Binds `addr`, or uses the `inherited` listening socket, and returns the listener along with a
+/// copy of its socket.
+fn bind(
+    addr: &BindAddress,
+    inherited: Option<StdListener>,
+    mode: Option<u32>,
+) -> io::Result<(Listener, StdListener)> {
+    let ln = match (inherited, addr) {
+        (Some(ln), _) => ln,
+        (None, BindAddress::Tcp(addr)) => StdListener::Tcp(std::net::TcpListener::bind(addr)?),
+        #[cfg(unix)]
+        (None, BindAddress::Unix(path)) => StdListener::Unix(bind_unix(path, mode)?),
+        #[cfg(not(unix))]
+        (None, BindAddress::Unix(_)) => {
+            let _ = mode;
+            return Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"));
+        }
+    };
+    let copy = ln.try_clone()?;
+    let ln = match ln {
+        StdListener::Tcp(ln) => {
+            ln.set_nonblocking(true)?;
+            Listener::Tcp(net::TcpListener::from_std(ln)?)
+        }
+        #[cfg(unix)]
+        StdListener::Unix(ln) => {
+            ln.set_nonblocking(true)?;
+            Listener::Unix(net::UnixListener::from_std(ln)?)
+        }
+    };
+    Ok((ln, copy))
 }
 
 /// Returns a future that listens, accepts and handles incoming connections.
+///
+/// When `proxies` is `Some`, connections from these addresses must start with a PROXY header.  On
+/// Unix sockets, all connections must then start with a PROXY header.  When `inherited` is
+/// `Some`, the binding uses this socket instead of binding `addr` itself.  `mode` is the
+/// permissions of the socket file of Unix sockets.
+#[allow(clippy::too_many_arguments)]
 pub async fn listen(
-    addr: SocketAddr,
+    addr: BindAddress,
+    inherited: Option<StdListener>,
+    mode: Option<u32>,
     shared: State,
+    throttle: Throttle,
     mut acceptor: Option<Arc<TlsAcceptor>>,
-    mut stop: mpsc::Sender<SocketAddr>,
+    mut proxies: Option<Arc<[Cidr]>>,
+    mut stop: mpsc::Sender<BindAddress>,
     mut commands: mpsc::Receiver<control::Command>,
 ) {
-    let mut ln = match net::TcpListener::bind(&addr).await {
+    let (mut ln, std_ln) = match bind(&addr, inherited, mode) {
         Ok(ln) => ln,
         Err(err) => {
             log::error!("Binding {} failed to come online: {}", addr, err);
@@ -113,12 +325,72 @@ pub async fn listen(
         log::info!("Binding {} online, accepting plain-text connections", addr);
     }
 
+    let mut socket_file = SocketFile(match &addr {
+        BindAddress::Unix(path) => Some(path.clone()),
+        BindAddress::Tcp(_) => None,
+    });
+
     loop {
         tokio::select! {
             maybe_conn = ln.accept() => match maybe_conn {
-                Ok((conn, peer_addr)) => match acceptor.as_ref() {
-                    Some(a) => handle_tls(conn, peer_addr, shared.clone(), a.clone()),
-                    None => handle_tcp(conn, peer_addr, shared.clone()),
+                Ok(Conn::Tcp(conn, peer_addr)) => {
+                    if let Err(err) = conn.set_keepalive(Some(time::Duration::from_secs(KEEPALIVE_SECS))) {
+                        log::warn!("Failed to set TCP keepalive for {}: {}", peer_addr, err);
+                        continue;
+                    }
+                    // Proxies are throttled too, until they send the address of the client.
+                    let guard = match throttle.connect(peer_addr.ip()) {
+                        Ok(guard) => guard,
+                        Err(reason) => {
+                            report_throttled(peer_addr, reason, &shared);
+                            continue;
+                        }
+                    };
+                    let peer = Peer {
+                        addr: peer_addr,
+                        binding: addr.clone(),
+                        local: false,
+                        tls: acceptor.is_some(),
+                        tls_info: None,
+                        certfp: None,
+                    };
+                    let is_proxy = proxies
+                        .as_ref()
+                        .filter(|proxies| proxies.iter().any(|cidr| cidr.contains(peer_addr.ip())))
+                        .is_some();
+                    let conn = handle_conn(
+                        conn,
+                        peer,
+                        shared.clone(),
+                        throttle.clone(),
+                        Some(guard),
+                        acceptor.clone(),
+                        is_proxy,
+                    );
+                    tokio::spawn(conn);
+                }
+                #[cfg(unix)]
+                Ok(Conn::Unix(conn)) => {
+                    let peer_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+                    let peer = Peer {
+                        addr: peer_addr,
+                        binding: addr.clone(),
+                        local: true,
+                        tls: acceptor.is_some(),
+                        tls_info: None,
+                        certfp: None,
+                    };
+                    let is_proxy = proxies.is_some();
+                    let conn = handle_conn(
+                        conn,
+                        peer,
+                        shared.clone(),
+                        throttle.clone(),
+                        None,
+                        acceptor.clone(),
+                        is_proxy,
+                    );
+                    tokio::spawn(conn);
                 }
                 Err(err) => log::warn!("Binding {} failed to accept a connection: {}", addr, err),
             },
@@ -137,6 +409,20 @@ pub async fn listen(
                     }
                     acceptor = Some(a);
                 }
+                Some(control::Command::ShareListener(sender)) => {
+                    let ln = std_ln.try_clone();
+                    if ln.is_ok() {
+                        // The socket now belongs to the new process as well.
+                        socket_file.0 = None;
+                    }
+                    let _ = sender.send(ln);
+                }
+                Some(control::Command::SetProxies(p)) => {
+                    if p.is_some() != proxies.is_some() {
+                        log::info!("Binding {} switched PROXY protocol {}", addr, if p.is_some() { "on" } else { "off" });
+                    }
+                    proxies = p;
+                }
                 None => {
                     log::info!("Binding {} now offline", addr);
                     return;
@@ -146,50 +432,135 @@ pub async fn listen(
     }
 }
 
-fn handle_tcp(conn: net::TcpStream, peer_addr: SocketAddr, shared: State) {
-    if let Err(err) = conn.set_keepalive(Some(time::Duration::from_secs(KEEPALIVE_SECS))) {
-        log::warn!("Failed to set TCP keepalive: {}", err);
-        return;
+/// Logs the refused connection, and tells operators the first time a subnet is throttled.
+fn report_throttled(peer_addr: SocketAddr, reason: Throttled, shared: &State) {
+    match reason {
+        Throttled::TooManyAttempts { subnet, first: true } => {
+            log::info!(peer:% = peer_addr; "{}: Throttled, too many connections from {}", peer_addr, subnet);
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                let text = format!("Throttling connections from {}", subnet);
+                shared.send_snotice(snomask::Kind::Flood, text).await;
+            });
+        }
+        Throttled::TooManyAttempts { .. } => {
+            log::debug!(peer:% = peer_addr; "{}: Throttled, too many connections", peer_addr);
+        }
+        Throttled::TooManyConnections { ip, first: true } => {
+            log::info!(peer:% = peer_addr; "{}: Throttled, too many simultaneous connections", peer_addr);
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                let text = format!("Throttling connections from {}, too many simultaneous connections", ip);
+                shared.send_snotice(snomask::Kind::Flood, text).await;
+            });
+        }
+        Throttled::TooManyConnections { .. } => {
+            log::debug!(peer:% = peer_addr; "{}: Throttled, too many simultaneous connections", peer_addr);
+        }
     }
-    tokio::spawn(handle(conn, peer_addr, shared));
 }
 
-fn handle_tls(
-    conn: net::TcpStream,
-    peer_addr: SocketAddr,
+/// Reads the PROXY header if `is_proxy` is true, throttles the connection, does the TLS handshake
+/// if `acceptor` is set and then handles the IRC connection.
+async fn handle_conn(
+    mut conn: impl io::AsyncRead + io::AsyncWrite + Unpin,
+    mut peer: Peer,
     shared: State,
-    acceptor: Arc<TlsAcceptor>,
+    throttle: Throttle,
+    mut _guard: Option<throttle::Guard>,
+    acceptor: Option<Arc<TlsAcceptor>>,
+    is_proxy: bool,
 ) {
-    if let Err(err) = conn.set_keepalive(Some(time::Duration::from_secs(KEEPALIVE_SECS))) {
-        log::warn!("Failed to set TCP keepalive for {}: {}", peer_addr, err);
-        return;
+    if is_proxy {
+        let proxy_timeout = time::Duration::from_secs(PROXY_TIMEOUT_SECS);
+        match time::timeout(proxy_timeout, proxy::read_header(&mut conn)).await {
+            Ok(Ok(Some(addr))) => {
+                // The connection now counts for the client, not for the proxy.
+                _guard = match throttle.connect(addr.ip()) {
+                    Ok(guard) => Some(guard),
+                    Err(reason) => {
+                        report_throttled(addr, reason, &shared);
+                        return;
+                    }
+                };
+                peer.addr = addr;
+                peer.local = false;
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => {
+                log::warn!(peer:% = peer.addr; "PROXY header from {} is invalid: {}", peer.addr, err);
+                return;
+            }
+            Err(_) => {
+                log::warn!(peer:% = peer.addr; "PROXY header from {} timed out", peer.addr);
+                return;
+            }
+        }
     }
-    tokio::spawn(async move {
-        let tls_handshake_timeout = time::Duration::from_secs(TLS_TIMEOUT_SECS);
-        let tls_handshake = time::timeout(tls_handshake_timeout, acceptor.accept(conn));
-        match tls_handshake.await {
-            Ok(Ok(tls_conn)) => handle(tls_conn, peer_addr, shared).await,
-            Ok(Err(err)) => log::warn!("TLS handshake with {} failed: {}", peer_addr, err),
-            Err(_) => log::warn!("TLS handshake with {} timed out", peer_addr),
+
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            handle(conn, peer, shared).await;
+            return;
         }
-    });
+    };
+
+    let tls_handshake_timeout = time::Duration::from_secs(TLS_TIMEOUT_SECS);
+    let tls_handshake = time::timeout(tls_handshake_timeout, acceptor.accept(conn));
+    match tls_handshake.await {
+        Ok(Ok(tls_conn)) => {
+            let (_, session) = tls_conn.get_ref();
+            if let (Some(version), Some(suite)) = (
+                session.get_protocol_version(),
+                session.get_negotiated_ciphersuite(),
+            ) {
+                peer.tls_info = Some(format!("{:?}, {:?}", version, suite.suite));
+            }
+            peer.certfp = session
+                .get_peer_certificates()
+                .and_then(|certs| certs.into_iter().next())
+                .map(|cert| util::fingerprint(&cert.0));
+            handle(tls_conn, peer, shared).await;
+        }
+        Ok(Err(err)) => {
+            log::warn!(peer:% = peer.addr; "TLS handshake with {} failed: {}", peer.addr, err);
+            shared.tls_handshake_failed().await;
+        }
+        Err(_) => {
+            log::warn!(peer:% = peer.addr; "TLS handshake with {} timed out", peer.addr);
+            shared.tls_handshake_failed().await;
+        }
+    }
 }
 
+/// Runs `$do` in a loop, and throttles it according to the given rate limit.
+///
+/// `$do` must return the number of points used, and may update `$limit`, which is read after each
+/// round so that rate limit changes are applied right away.  When `$limit` is `None`, `$do` is not
+/// throttled.  `$limited` is awaited each time `$do` is throttled.
 macro_rules! rate_limit {
-    ( $rate:expr, $burst:expr, $do:expr ) => {{
-        let rate: u32 = $rate;
-        let burst: u32 = $burst;
+    ( $limit:ident, $do:expr, $limited:expr ) => {{
         let mut used_points: u32 = 0;
         let mut last_round = time::Instant::now();
 
         loop {
             used_points = match $do.await {
-                Ok(points) => used_points + points,
+                Ok(points) => used_points.saturating_add(points),
                 Err(err) => {
                     let res: io::Result<()> = Err(err);
                     break res;
                 }
             };
+            let limit: Option<config::RateLimit> = $limit;
+            let (rate, burst) = match limit {
+                Some(limit) => (limit.rate.max(1), limit.burst),
+                None => {
+                    used_points = 0;
+                    last_round = time::Instant::now();
+                    continue;
+                }
+            };
             if burst < used_points {
                 let elapsed = last_round.elapsed();
                 let millis = elapsed.as_millis();
@@ -203,8 +574,9 @@ macro_rules! rate_limit {
                 last_round += elapsed;
 
                 if burst < used_points {
-                    let wait_millis = (used_points - burst) * rate;
+                    let wait_millis = (used_points - burst).saturating_mul(rate);
                     let wait = time::Duration::from_millis(wait_millis as u64);
+                    $limited.await;
                     time::delay_for(wait).await;
                     used_points = burst;
                     last_round += wait;
@@ -215,17 +587,31 @@ macro_rules! rate_limit {
 }
 
 /// Returns a future that handles an IRC connection.
-async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer_addr: SocketAddr, shared: State) {
+async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer: Peer, shared: State) {
+    use io::AsyncWriteExt as _;
+
+    let peer_addr = peer.addr;
     let (reader, mut writer) = io::split(conn);
     let mut reader = IrcReader::new(reader, 512);
 
     let (msg_queue, mut outgoing_msgs) = sync::mpsc::unbounded_channel();
-    let peer_id = shared.peer_joined(peer_addr, msg_queue).await;
+    let sendq = Arc::new(SendQ::default());
+    let peer_id = match shared.peer_joined(peer, msg_queue, sendq.clone()).await {
+        Some(peer_id) => peer_id,
+        None => {
+            // The connection has been refused, send the ERROR message and close the connection.
+            while let Some(msg) = outgoing_msgs.recv().await {
+                let _ = writer.write_all(msg.as_ref().as_bytes()).await;
+            }
+            return;
+        }
+    };
     tokio::spawn(login_timeout(peer_id, shared.clone()));
 
     let incoming = async {
         let mut buf = String::new();
-        rate_limit!(125, 32, async {
+        let mut limit = shared.rate_limit(peer_id).await;
+        rate_limit!(limit, async {
             buf.clear();
             let n = reader.read_message(&mut buf).await?;
             if n == 0 {
@@ -235,23 +621,36 @@ async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer_addr: SocketAddr
                 ));
             }
             log::trace!("{} >> {}", peer_addr, buf.trim());
-            Ok(handle_buffer(peer_id, &buf, &shared).await)
-        })
+            Ok(handle_buffer(peer_id, &buf, &shared, &mut limit).await)
+        }, shared.rate_limited(peer_id))
     };
 
     let outgoing = async {
-        use io::AsyncWriteExt as _;
-
         while let Some(msg) = outgoing_msgs.recv().await {
-            writer.write_all(msg.as_ref().as_bytes()).await?;
+            let msg = msg.as_ref();
+            writer.write_all(msg.as_bytes()).await?;
+            sendq.sent(msg.len());
         }
         Ok(())
     };
 
+    // Runs with the connection, so that it stops before `peer_id` can be given to another client.
+    let pings = async {
+        while let Some(delay) = shared.check_ping(peer_id).await {
+            time::delay_for(time::Duration::from_millis(delay)).await;
+        }
+        // The client has been removed, let `outgoing` send its last messages.
+        std::future::pending::<()>().await
+    };
+
     let res: Option<io::Error>;
     tokio::select! {
         r = incoming => res = r.err(),
         r = outgoing => res = r.err(),
+        _ = pings => res = None,
+        _ = sendq.exceeded() => {
+            res = Some(io::Error::new(io::ErrorKind::Other, lines::SENDQ_EXCEEDED));
+        }
     }
 
     shared.peer_quit(peer_id, res).await;
@@ -259,11 +658,18 @@ async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer_addr: SocketAddr
 
 /// Handle a line from the client.
 ///
-/// Returns `None` if the connection must be closed, `Some(points)` otherwise.  Points are used for
-/// rate limits.
-async fn handle_buffer(peer_id: usize, buf: &str, shared: &State) -> u32 {
+/// Returns the number of points used by the message, and updates `limit` with the rate limit the
+/// client is currently subject to.
+async fn handle_buffer(
+    peer_id: usize,
+    buf: &str,
+    shared: &State,
+    limit: &mut Option<config::RateLimit>,
+) -> u32 {
     if let Some(msg) = Message::parse(buf) {
-        return shared.handle_message(peer_id, msg).await;
+        let (points, new_limit) = shared.handle_message(peer_id, msg, buf.len()).await;
+        *limit = new_limit;
+        return points;
     }
     1
 }
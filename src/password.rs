@@ -0,0 +1,91 @@
+//! Passwords stored in the configuration.
+//!
+//! Operator, server, class and WEBIRC passwords are either in plain text or hashed.  Hashes are
+//! detected by their prefix:
+//!
+//! - `$argon2id$`, `$argon2i$` and `$argon2d$` for Argon2, in the PHC string format,
+//! - `$scrypt$` for scrypt, in the PHC string format,
+//! - `$2a$`, `$2b$`, `$2x$` and `$2y$` for bcrypt.
+//!
+//! `ellidri mkpasswd` prints Argon2id hashes, see `hash`.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::Argon2;
+use scrypt::Scrypt;
+
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// Whether `password` matches `stored`, a password from the configuration.
+///
+/// Plain-text passwords are compared in constant time.  Returns false if `stored` is an invalid
+/// hash.
+pub fn verify(stored: &str, password: &str) -> bool {
+    if stored.starts_with("$argon2") || stored.starts_with("$scrypt$") {
+        let hash = match PasswordHash::new(stored) {
+            Ok(hash) => hash,
+            Err(err) => {
+                log::warn!("Invalid password hash in the configuration: {}", err);
+                return false;
+            }
+        };
+        if hash.algorithm.as_str() == "scrypt" {
+            Scrypt.verify_password(password.as_bytes(), &hash).is_ok()
+        } else {
+            Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+        }
+    } else if BCRYPT_PREFIXES.iter().any(|prefix| stored.starts_with(prefix)) {
+        match bcrypt::verify(password, stored) {
+            Ok(matches) => matches,
+            Err(err) => {
+                log::warn!("Invalid password hash in the configuration: {}", err);
+                false
+            }
+        }
+    } else {
+        ring::constant_time::verify_slices_are_equal(stored.as_bytes(), password.as_bytes()).is_ok()
+    }
+}
+
+/// Returns the Argon2id hash of `password`, with a random salt.
+pub fn hash(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 failed to hash a password")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        assert!(verify("hunter2", "hunter2"));
+        assert!(!verify("hunter2", "hunter3"));
+        assert!(!verify("hunter2", ""));
+
+        let argon2 = hash("hunter2");
+        assert!(argon2.starts_with("$argon2id$"));
+        assert!(verify(&argon2, "hunter2"));
+        assert!(!verify(&argon2, "hunter3"));
+
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        assert!(verify(&bcrypt, "hunter2"));
+        assert!(!verify(&bcrypt, "hunter3"));
+
+        let salt = SaltString::generate(&mut OsRng);
+        let params = scrypt::Params::new(4, 8, 1, 32).unwrap();
+        let scrypt = Scrypt
+            .hash_password_customized(b"hunter2", None, None, params, &salt)
+            .unwrap()
+            .to_string();
+        assert!(scrypt.starts_with("$scrypt$"));
+        assert!(verify(&scrypt, "hunter2"));
+        assert!(!verify(&scrypt, "hunter3"));
+
+        assert!(!verify("$argon2id$garbage", "hunter2"));
+        assert!(!verify("$2b$garbage", "hunter2"));
+    }
+} // mod tests
@@ -0,0 +1,175 @@
+//! PROXY protocol support.
+//!
+//! Reverse proxies and TLS terminators can send a PROXY header at the beginning of the connection
+//! to give the address of the client they forward.  Both the text (v1) and binary (v2) versions
+//! of the header are supported.
+//!
+//! See <https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{self, AsyncRead, AsyncReadExt as _};
+
+/// Maximum length of a v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Length of the shortest v1 header, "PROXY UNKNOWN\r\n".
+const V1_MIN_LEN: usize = 15;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the fixed part of a v2 header.
+const V2_HEADER_LEN: usize = 16;
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads a PROXY header from `conn`, without reading past its end.
+///
+/// Returns the address of the client, or `None` if the proxy did not give one (e.g. for health
+/// checks).
+pub async fn read_header(conn: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    let mut buf = [0; V1_MAX_LEN];
+    conn.read_exact(&mut buf[..V1_MIN_LEN]).await?;
+
+    if buf.starts_with(V2_SIGNATURE) {
+        let mut header = [0; V2_HEADER_LEN];
+        header[..V1_MIN_LEN].copy_from_slice(&buf[..V1_MIN_LEN]);
+        conn.read_exact(&mut header[V1_MIN_LEN..]).await?;
+        let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+        let mut body = vec![0; len];
+        conn.read_exact(&mut body).await?;
+        return parse_v2(&header, &body);
+    }
+
+    let mut len = V1_MIN_LEN;
+    while buf[len - 1] != b'\n' {
+        if len == V1_MAX_LEN {
+            return Err(invalid("PROXY header too long"));
+        }
+        conn.read_exact(&mut buf[len..=len]).await?;
+        len += 1;
+    }
+    parse_v1(&buf[..len])
+}
+
+/// Parses a v1 header, e.g. "PROXY TCP4 192.0.2.1 192.0.2.2 41234 6667\r\n".
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY header is not UTF-8"))?;
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or_else(|| invalid("PROXY header must end with CRLF"))?;
+    let mut words = line.split(' ');
+
+    if words.next() != Some("PROXY") {
+        return Err(invalid("not a PROXY header"));
+    }
+    match words.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown PROXY protocol")),
+    }
+
+    let src: IpAddr = words
+        .next()
+        .and_then(|w| w.parse().ok())
+        .ok_or_else(|| invalid("invalid PROXY source address"))?;
+    let _dst = words.next();
+    let port: u16 = words
+        .next()
+        .and_then(|w| w.parse().ok())
+        .ok_or_else(|| invalid("invalid PROXY source port"))?;
+
+    Ok(Some(SocketAddr::new(src, port)))
+}
+
+/// Parses a v2 header, given its fixed part and the rest of it.
+fn parse_v2(header: &[u8], body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if !header.starts_with(V2_SIGNATURE) {
+        return Err(invalid("not a PROXY header"));
+    }
+    match header[12] {
+        0x20 => return Ok(None), // LOCAL
+        0x21 => {}               // PROXY
+        _ => return Err(invalid("unknown PROXY version or command")),
+    }
+
+    match header[13] {
+        0x11 if 12 <= body.len() => {
+            let mut src = [0; 4];
+            src.copy_from_slice(&body[..4]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(src).into(), port)))
+        }
+        0x21 if 36 <= body.len() => {
+            let mut src = [0; 16];
+            src.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(src).into(), port)))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY header too short")),
+        // UDP, UNIX sockets or unspecified: the address is useless.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut res = V2_SIGNATURE.to_vec();
+        res.push(command);
+        res.push(family);
+        res.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        res.extend_from_slice(body);
+        res
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"PROXY TCP4 192.0.2.1 192.0.2.2 41234 6667\r\n", Some("192.0.2.1:41234")),
+            (b"PROXY TCP6 2001:db8::1 2001:db8::2 41234 6697\r\n", Some("[2001:db8::1]:41234")),
+            (b"PROXY UNKNOWN\r\n", None),
+            (b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n", None),
+        ];
+        for (line, expected) in cases {
+            let expected = expected.map(|addr| addr.parse().unwrap());
+            assert_eq!(parse_v1(line).unwrap(), expected, "{:?}", line);
+        }
+
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 192.0.2.2 41234 6667\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 nope 192.0.2.2 41234 6667\r\n").is_err());
+        assert!(parse_v1(b"NICK ellidri\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let tcp4 = [192, 0, 2, 1, 192, 0, 2, 2, 0xa0, 0x12, 0x1a, 0x0b];
+        let header = v2(0x21, 0x11, &tcp4);
+        let expected = "192.0.2.1:40978".parse().unwrap();
+        assert_eq!(parse_v2(&header[..16], &header[16..]).unwrap(), Some(expected));
+
+        let header = v2(0x20, 0x00, &[]);
+        assert_eq!(parse_v2(&header[..16], &header[16..]).unwrap(), None);
+
+        let header = v2(0x21, 0x11, &tcp4[..6]);
+        assert!(parse_v2(&header[..16], &header[16..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 41234 6667\r\nNICK senpai\r\n";
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:41234".parse().unwrap()));
+        assert_eq!(input, b"NICK senpai\r\n");
+
+        let mut header = v2(0x21, 0x21, &[0; 36]);
+        header.extend_from_slice(b"NICK senpai\r\n");
+        let mut input = &header[..];
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("[::]:0".parse().unwrap()));
+        assert_eq!(input, b"NICK senpai\r\n");
+    }
+} // mod tests
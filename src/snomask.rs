@@ -0,0 +1,176 @@
+//! Server notice masks.
+//!
+//! Operators with the `+s` user mode receive server notices about the events they have selected
+//! with `MODE nick +s <snomask>`.  Each kind of event has a letter.  The snomask either lists the
+//! letters to select (e.g. "cknf"), or adds and removes letters from the current selection (e.g.
+//! "+k-f").
+
+use std::fmt;
+
+/// A kind of server notice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A client has registered, or its connection was refused.
+    Connect,
+
+    /// A registered client has quit.
+    Quit,
+
+    /// A client has changed its nickname.
+    Nick,
+
+    /// A client has been killed.
+    Kill,
+
+    /// A server ban has been added or removed, or has disconnected a client.
+    Ban,
+
+    /// Connections have been throttled, or a client has hit its rate limit.
+    Flood,
+
+    /// A client has used OPER.
+    Oper,
+
+    /// A client has given a wrong password, or an address or an operator name has been locked out.
+    Auth,
+
+    /// The configuration has been reloaded, or failed to.
+    Rehash,
+}
+
+const KINDS: [Kind; 9] = [
+    Kind::Auth,
+    Kind::Ban,
+    Kind::Connect,
+    Kind::Flood,
+    Kind::Kill,
+    Kind::Nick,
+    Kind::Oper,
+    Kind::Quit,
+    Kind::Rehash,
+];
+
+impl Kind {
+    pub fn letter(self) -> char {
+        match self {
+            Self::Connect => 'c',
+            Self::Quit => 'q',
+            Self::Nick => 'n',
+            Self::Kill => 'k',
+            Self::Ban => 'b',
+            Self::Flood => 'f',
+            Self::Oper => 'o',
+            Self::Rehash => 'r',
+            Self::Auth => 'a',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        KINDS.iter().cloned().find(|kind| kind.letter() == letter)
+    }
+
+    /// The name of this kind, put at the start of notices.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Connect => "CONNECT",
+            Self::Quit => "QUIT",
+            Self::Nick => "NICK",
+            Self::Kill => "KILL",
+            Self::Ban => "BAN",
+            Self::Flood => "FLOOD",
+            Self::Oper => "OPER",
+            Self::Rehash => "REHASH",
+            Self::Auth => "AUTH",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A set of kinds of server notices.  The user mode `+s` is set when it is not empty.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snomask(u32);
+
+impl Snomask {
+    pub fn all() -> Self {
+        Self(KINDS.iter().fold(0, |mask, kind| mask | kind.bit()))
+    }
+
+    /// Returns the snomask with the given letters.  Unknown letters are ignored.
+    pub fn from_letters(letters: &str) -> Self {
+        let mut mask = Self::default();
+        mask.apply(letters);
+        mask
+    }
+
+    /// Returns the kinds that are in both snomasks.
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, kind: Kind) -> bool {
+        self.0 & kind.bit() != 0
+    }
+
+    /// Applies the given snomask.  Unknown letters are ignored.
+    pub fn apply(&mut self, changes: &str) {
+        if !changes.starts_with(['+', '-']) {
+            self.0 = 0;
+        }
+        let mut value = true;
+        for c in changes.chars() {
+            match c {
+                '+' => value = true,
+                '-' => value = false,
+                c => {
+                    if let Some(kind) = Kind::from_letter(c) {
+                        if value {
+                            self.0 |= kind.bit();
+                        } else {
+                            self.0 &= !kind.bit();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Snomask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("+")?;
+        for kind in KINDS.iter().filter(|kind| self.contains(**kind)) {
+            write!(f, "{}", kind.letter())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snomask_apply() {
+        let mut mask = Snomask::default();
+        mask.apply("nkcx");
+        assert_eq!(mask.to_string(), "+ckn");
+
+        mask.apply("+f-c");
+        assert_eq!(mask.to_string(), "+fkn");
+
+        mask.apply("q");
+        assert_eq!(mask.to_string(), "+q");
+
+        mask.apply("-q");
+        assert!(mask.is_empty());
+
+        assert_eq!(Snomask::all().to_string(), "+abcfknoqr");
+    }
+} // mod tests
@@ -0,0 +1,185 @@
+//! Queries and actions of the admin socket, see `crate::admin`.
+
+use crate::{snomask, util};
+use ellidri_tokens::{Buffer, Command};
+use ellidri_unicase::u;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Reads the server bans saved at `path` by `save_bans`.
+pub fn load_bans(path: Option<&Path>) -> HashMap<String, String> {
+    let path = match path {
+        Some(path) => path,
+        None => return HashMap::new(),
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        // No ban has been saved yet.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            log::warn!("Failed to read {:?}: {}", path.display(), err);
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_str::<HashMap<String, String>>(&contents) {
+        Ok(bans) => {
+            log::info!("Loaded {} bans from {:?}", bans.len(), path.display());
+            bans
+        }
+        Err(err) => {
+            log::error!("Failed to read the bans in {:?}: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes the server bans to `path`, as a JSON object that maps masks to reasons.
+///
+/// The file is replaced at once, so that it is never left half-written.
+pub fn save_bans(path: &Path, bans: &HashMap<String, String>) {
+    let tmp = path.with_extension("tmp");
+    let res = serde_json::to_vec_pretty(bans)
+        .map_err(std::io::Error::from)
+        .and_then(|contents| fs::write(&tmp, contents))
+        .and_then(|()| fs::rename(&tmp, path));
+    if let Err(err) = res {
+        log::error!("Failed to save the bans to {:?}: {}", path.display(), err);
+    }
+}
+
+impl super::StateInner {
+    pub fn admin_clients(&self) -> Value {
+        let clients: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(id, client)| {
+                let class = client.class.map(|class| &self.classes[class].name);
+                json!({
+                    "id": id,
+                    "nick": client.nick(),
+                    "user": client.user(),
+                    "real": client.real(),
+                    "host": client.host(),
+                    "ip": client.ip().to_string(),
+                    "account": client.account(),
+                    "registered": client.is_registered(),
+                    "operator": client.operator,
+                    "tls": client.tls(),
+                    "binding": client.binding().to_string(),
+                    "class": class,
+                    "gateway": client.gateway,
+                    "signon_time": client.signon_time(),
+                    "idle_time": client.idle_time(),
+                })
+            })
+            .collect();
+        Value::from(clients)
+    }
+
+    pub fn admin_channels(&self) -> Value {
+        let channels: Vec<_> = self
+            .channels
+            .iter()
+            .map(|(name, channel)| {
+                let topic = channel.topic.as_ref().map(|topic| &topic.content);
+                json!({
+                    "name": name.get(),
+                    "members": channel.members.len(),
+                    "topic": topic,
+                })
+            })
+            .collect();
+        Value::from(channels)
+    }
+
+    pub fn admin_stats(&self) -> Value {
+        let registered = self.clients.iter().filter(|(_, c)| c.is_registered()).count();
+        let operators = self.clients.iter().filter(|(_, c)| c.operator).count();
+        json!({
+            "created_at": self.created_at,
+            "clients": self.clients.len(),
+            "registered": registered,
+            "operators": operators,
+            "channels": self.channels.len(),
+            "bans": self.bans.len(),
+        })
+    }
+
+    pub fn bans(&self) -> Value {
+        let bans: Vec<_> = self
+            .bans
+            .iter()
+            .map(|(mask, reason)| json!({ "mask": mask, "reason": reason }))
+            .collect();
+        Value::from(bans)
+    }
+
+    pub fn kill(&mut self, nick: &str, reason: &str) -> bool {
+        let id = match self.nicks.get(u(nick)) {
+            Some(&id) => id,
+            None => return false,
+        };
+        log::info!("{}: Killed through the admin socket: {}", id, reason);
+        let text = format!("{} killed by the control socket: {}", self.clients[id].full_name(), reason);
+        self.send_snotice(snomask::Kind::Kill, text);
+        self.remove_client(id, format_args!("Killed: {}", reason), "Killed");
+        true
+    }
+
+    /// Returns the reason of the first ban that matches the given client, if any.
+    ///
+    /// Bans are matched against both the host and the IP address of the client.
+    pub fn ban_reason(&self, id: usize) -> Option<&str> {
+        let client = &self.clients[id];
+        let ip_name = format!("{}!{}@{}", client.nick(), client.user(), client.ip());
+        self.bans
+            .iter()
+            .find(|(mask, _)| {
+                util::match_mask(mask, client.full_name()) || util::match_mask(mask, &ip_name)
+            })
+            .map(|(_, reason)| reason.as_str())
+    }
+
+    pub fn add_ban(&mut self, mask: String, reason: String) -> usize {
+        log::info!("Banned {:?}: {}", mask, reason);
+        self.send_snotice(snomask::Kind::Ban, format_args!("Ban added on {}: {}", mask, reason));
+        let text = format!("Banned: {}", reason);
+        self.bans.insert(mask, reason);
+
+        let banned: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(id, client)| client.is_registered() && self.ban_reason(*id).is_some())
+            .map(|(id, _)| id)
+            .collect();
+        for &id in &banned {
+            self.remove_client(id, &text, "Banned");
+        }
+        banned.len()
+    }
+
+    pub fn remove_ban(&mut self, mask: &str) -> bool {
+        let removed = self.bans.remove(mask).is_some();
+        if removed {
+            log::info!("Unbanned {:?}", mask);
+            self.send_snotice(snomask::Kind::Ban, format_args!("Ban removed on {}", mask));
+        }
+        removed
+    }
+
+    pub fn send_global_notice(&self, text: &str) -> usize {
+        let mut count = 0;
+        for (_, client) in self.clients.iter().filter(|(_, c)| c.is_registered()) {
+            let mut notice = Buffer::new();
+            notice
+                .message(&self.domain, Command::Notice)
+                .param(client.nick())
+                .trailing_param(text);
+            client.send(notice);
+            count += 1;
+        }
+        count
+    }
+}
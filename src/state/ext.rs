@@ -0,0 +1,314 @@
+//! Handlers for commands that are not part of the RFCs nor IRCv3, but are common among servers.
+
+use super::{find_nick, CommandContext, HandlerResult as Result};
+use crate::config::Privilege;
+use crate::{data, lines};
+use ellidri_tokens::{rpl, ReplyBuffer};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+impl super::StateInner {
+    // WEBIRC
+
+    /// Handler for the WEBIRC command.
+    ///
+    /// <https://ircv3.net/specs/extensions/webirc>
+    pub fn cmd_webirc(
+        &mut self,
+        ctx: CommandContext<'_>,
+        args: data::req::WebIrc<'_>,
+        verified: &[String],
+    ) -> Result {
+        let client = &self.clients[ctx.id];
+        let gateway_ip = client.ip();
+
+        let gateway = self.webirc.iter().find(|gateway| {
+            gateway.hosts.iter().any(|cidr| cidr.contains(gateway_ip))
+                && verified.contains(&gateway.password)
+        });
+        let gateway = match gateway {
+            Some(gateway) if client.gateway.is_none() => gateway.name.clone(),
+            _ => {
+                log::debug!("{}:     Refused gateway {:?}", ctx.id, args.gateway);
+                self.remove_client(ctx.id, lines::WEBIRC_REFUSED, "");
+                return Err(());
+            }
+        };
+        let ip: IpAddr = match args.ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                log::debug!("{}:     Invalid address {:?}", ctx.id, args.ip);
+                self.remove_client(ctx.id, lines::WEBIRC_REFUSED, "");
+                return Err(());
+            }
+        };
+        let secure = args.flags.split(' ').any(|flag| flag == "secure");
+
+        log::debug!("{}:     Gateway {:?} forwards {}", ctx.id, gateway, ip);
+        let host = args.hostname.map_or_else(|| ip.to_string(), |host| host.get().to_owned());
+        let client = &mut self.clients[ctx.id];
+        client.gateway = Some(gateway);
+        client.set_ip(ip);
+        client.set_tls(secure);
+        client.set_real_host(&host);
+        client.local = false;
+
+        if let Err(reason) = self.update_class(ctx.id) {
+            log::debug!("{}:     Rejected: {}", ctx.id, reason);
+            self.remove_client(ctx.id, reason, "");
+            return Err(());
+        }
+
+        Ok(())
+    }
+
+    // SAJOIN, SAPART, SANICK, SAMODE and CHGHOST
+
+    /// Returns the client targeted by an operator command, if the issuer has the given privilege.
+    fn find_target(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        privilege: Privilege,
+        who: data::Nickname<'_>,
+    ) -> std::result::Result<usize, ()> {
+        if !self.clients[ctx.id].has_privilege(privilege) {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            return Err(());
+        }
+        find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, who).map(|(target, _)| target)
+    }
+
+    /// Runs `handler` as if `target` had issued the command, so that it receives the replies and
+    /// other clients are notified as usual.
+    fn on_behalf_of<F>(&mut self, id: usize, target: usize, handler: F) -> Result
+    where
+        F: FnOnce(&mut Self, CommandContext<'_>) -> Result,
+    {
+        let mut rb = self.clients[target].reply("");
+        let ctx = CommandContext {
+            id: target,
+            rb: &mut rb,
+            client_tags: "",
+        };
+        let res = handler(self, ctx);
+        if let Some(target) = self.clients.get(target) {
+            if !rb.is_empty() {
+                target.send(rb);
+            }
+        }
+        // `reply` changed the nickname used in the replies to the issuer.
+        ReplyBuffer::set_nick(self.clients[id].nick());
+        res
+    }
+
+    /// Handler for the SAJOIN command.
+    ///
+    /// Joins a client to channels, whatever their modes.
+    pub fn cmd_sajoin(&mut self, mut ctx: CommandContext<'_>, args: data::req::SaJoin<'_>) -> Result {
+        let target = self.find_target(&mut ctx, Privilege::ManageUsers, args.who)?;
+        let channels: Vec<_> = args.channels.iter().map(|(name, _)| name.get().to_owned()).collect();
+        self.audit(ctx.id, "sajoin", json!({
+            "target": self.clients[target].full_name(),
+            "channels": channels,
+        }));
+        self.on_behalf_of(ctx.id, target, |state, ctx| state.join(ctx, args.channels, true))
+    }
+
+    /// Handler for the SAPART command.
+    ///
+    /// Makes a client leave channels.
+    pub fn cmd_sapart(&mut self, mut ctx: CommandContext<'_>, args: data::req::SaPart<'_>) -> Result {
+        let target = self.find_target(&mut ctx, Privilege::ManageUsers, args.who)?;
+        let mut res = Ok(());
+        for channel_name in args.part.from.iter() {
+            let is_member = self
+                .channels
+                .get(channel_name.u())
+                .is_some_and(|channel| channel.members.contains_key(&target));
+            if !is_member {
+                log::debug!("{}:     {:?} not on {:?}", ctx.id, args.who.get(), channel_name.get());
+                ctx.rb
+                    .reply(rpl::ERR_USERNOTINCHANNEL)
+                    .param(args.who.get())
+                    .param(channel_name.get())
+                    .trailing_param(lines::USER_NOT_IN_CHANNEL);
+                res = Err(());
+            }
+        }
+        res?;
+
+        let channels: Vec<_> = args.part.from.iter().map(|name| name.get().to_owned()).collect();
+        self.audit(ctx.id, "sapart", json!({
+            "target": self.clients[target].full_name(),
+            "channels": channels,
+            "reason": args.part.reason,
+        }));
+        self.on_behalf_of(ctx.id, target, |state, ctx| state.cmd_part(ctx, args.part))
+    }
+
+    /// Handler for the SANICK command.
+    ///
+    /// Changes the nickname of a client.
+    pub fn cmd_sanick(&mut self, mut ctx: CommandContext<'_>, args: data::req::SaNick<'_>) -> Result {
+        let target = self.find_target(&mut ctx, Privilege::ManageUsers, args.who)?;
+        if self.nicks.get(args.nick.u()).is_some_and(|id| *id != target) {
+            log::debug!("{}:     Already in use", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_NICKNAMEINUSE)
+                .param(args.nick.get())
+                .trailing_param(lines::NICKNAME_IN_USE);
+            return Err(());
+        }
+
+        self.audit(ctx.id, "sanick", json!({
+            "target": self.clients[target].full_name(),
+            "nick": args.nick.get(),
+        }));
+        self.on_behalf_of(ctx.id, target, |state, ctx| state.cmd_nick(ctx, args.nick))
+    }
+
+    /// Handler for the SAMODE command.
+    ///
+    /// Changes the modes of a channel without being on it.  The change is recorded in the audit
+    /// log by `mode_channel_set`, like other operator mode changes.
+    pub fn cmd_samode(&mut self, ctx: CommandContext<'_>, args: data::req::ModeChannelSet<'_>) -> Result {
+        if !self.clients[ctx.id].has_privilege(Privilege::OverrideChannel) {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            return Err(());
+        }
+        self.mode_channel_set(ctx, args, true)
+    }
+
+    /// Handler for the CHGHOST command.
+    ///
+    /// Changes the username and host of a client.
+    pub fn cmd_chghost(&mut self, mut ctx: CommandContext<'_>, args: data::req::ChgHost<'_>) -> Result {
+        let target = self.find_target(&mut ctx, Privilege::ManageUsers, args.who)?;
+        let valid = |s: &str| data::HostName::try_from(s).is_ok() && !s.contains(['!', '@']);
+        if !valid(args.user) || self.userlen < args.user.len() || !valid(args.host) {
+            log::debug!("{}:     Bad user or host", ctx.id);
+            ctx.rb
+                .message("", "FAIL")
+                .param("CHGHOST")
+                .param("INVALID_HOST")
+                .trailing_param(lines::INVALID_HOST);
+            return Err(());
+        }
+
+        self.audit(ctx.id, "chghost", json!({
+            "target": self.clients[target].full_name(),
+            "user": args.user,
+            "host": args.host,
+        }));
+        self.clients[target].vhost = Some(args.host.to_owned());
+        self.on_behalf_of(ctx.id, target, |state, ctx| {
+            state.change_host(ctx.id, ctx.rb, args.user, args.host);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::*;
+
+    #[tokio::test]
+    async fn test_sajoin_sapart() {
+        let state = simple_state().await;
+        let (user, mut user_q) = add_registered_client(&state, "user", "").await;
+        let (target, mut target_q) = add_registered_client(&state, "target", "").await;
+
+        handle_message(&state, user, "SAJOIN target #chan").await;
+        assert!(collect(&mut user_q).contains(" 481 user "));
+        assert_eq!(collect(&mut target_q), "");
+
+        let (admin, mut admin_q) = add_oper(&state).await;
+        handle_message(&state, user, "JOIN #chan").await;
+        handle_message(&state, user, "MODE #chan +k secret").await;
+        flush(&mut user_q);
+        handle_message(&state, target, "JOIN #chan").await;
+        assert!(collect(&mut target_q).contains(" 475 target #chan "));
+        handle_message(&state, admin, "SAJOIN target #chan").await;
+        assert!(collect(&mut target_q).starts_with(":target!~target@127.0.0.1 JOIN #chan\r\n"));
+        assert_eq!(collect(&mut user_q), ":target!~target@127.0.0.1 JOIN #chan\r\n");
+        assert_eq!(collect(&mut admin_q), "");
+
+        handle_message(&state, admin, "SAPART target #chan,#other :Bye").await;
+        assert!(collect(&mut admin_q).contains(" 441 admin target #other "));
+        assert_eq!(collect(&mut target_q), "");
+
+        handle_message(&state, admin, "SAPART target #chan :Bye").await;
+        let part = ":target!~target@127.0.0.1 PART #chan :Bye\r\n";
+        assert_eq!(collect(&mut target_q), part);
+        assert_eq!(collect(&mut user_q), part);
+    }
+
+    #[tokio::test]
+    async fn test_sanick() {
+        let state = simple_state().await;
+        let (_, mut user_q) = add_registered_client(&state, "user", "").await;
+        let (_, mut target_q) = add_registered_client(&state, "target", "").await;
+        let (admin, mut admin_q) = add_oper(&state).await;
+
+        handle_message(&state, admin, "SANICK target user").await;
+        assert!(collect(&mut admin_q).contains(" 433 admin user "));
+        assert_eq!(collect(&mut target_q), "");
+
+        handle_message(&state, admin, "SANICK target renamed").await;
+        assert_eq!(collect(&mut target_q), ":target!~target@127.0.0.1 NICK renamed\r\n");
+        assert_eq!(collect(&mut user_q), "");
+
+        handle_message(&state, admin, "SANICK renamed target").await;
+        assert_eq!(collect(&mut target_q), ":renamed!~target@127.0.0.1 NICK target\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_chghost() {
+        let state = simple_state().await;
+        let (target, mut target_q) = add_registered_client(&state, "target", "").await;
+        let (capable, mut capable_q) = add_registered_client(&state, "capable", "chghost").await;
+        let (other, mut other_q) = add_registered_client(&state, "other", "extended-join").await;
+        let (admin, mut admin_q) = add_oper(&state).await;
+        for id in &[target, capable, other] {
+            handle_message(&state, *id, "JOIN #chan").await;
+        }
+        for queue in &mut [&mut target_q, &mut capable_q, &mut other_q] {
+            flush(queue);
+        }
+
+        handle_message(&state, admin, "CHGHOST target bad@user host.example").await;
+        assert!(collect(&mut admin_q).starts_with("FAIL CHGHOST INVALID_HOST "));
+        handle_message(&state, admin, "CHGHOST target user bad!host").await;
+        assert!(collect(&mut admin_q).starts_with("FAIL CHGHOST INVALID_HOST "));
+        assert_eq!(collect(&mut target_q), "");
+
+        handle_message(&state, admin, "CHGHOST target user host.example").await;
+        assert_eq!(collect(&mut admin_q), "");
+        assert_eq!(
+            collect(&mut target_q),
+            ":ellidri.localdomain 396 target host.example :is now your displayed host\r\n",
+        );
+        assert_eq!(
+            collect(&mut capable_q),
+            ":target!~target@127.0.0.1 CHGHOST ~user host.example\r\n",
+        );
+        assert_eq!(collect(&mut other_q), concat!(
+            ":target!~target@127.0.0.1 QUIT :Changing host\r\n",
+            ":target!~user@host.example JOIN #chan * :target\r\n",
+            ":ellidri.localdomain MODE #chan +o target\r\n",
+        ));
+
+        handle_message(&state, target, "CAP REQ chghost").await;
+        flush(&mut target_q);
+        handle_message(&state, admin, "CHGHOST target user other.example").await;
+        assert_eq!(
+            collect(&mut target_q),
+            ":target!~user@host.example CHGHOST ~user other.example\r\n",
+        );
+    }
+} // mod tests
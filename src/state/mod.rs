@@ -2,17 +2,26 @@
 
 #![allow(clippy::needless_pass_by_value)]
 
-use crate::{Channel, Client, config, data, lines, util};
-use crate::client::{MessageQueue, MessageQueueItem};
+use crate::{Channel, Client, audit, config, data, lines, lockout, metrics, password, snomask, util};
+use crate::client::{MessageQueue, MessageQueueItem, Peer, SendQ};
+use crate::config::Privilege;
 use crate::data::Request;
+use crate::snomask::Snomask;
 use ellidri_tokens::{mode, rpl, Buffer, Command, Message, ReplyBuffer};
 use ellidri_unicase::{u, UniCase};
 use slab::Slab;
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 use std::{fmt, fs, net};
 use tokio::sync::{Mutex, Notify};
+use tokio::task;
 
+mod admin;
+mod ext;
+#[cfg(test)]
+mod test;
 mod v1;
 mod v3;
 
@@ -55,9 +64,15 @@ pub struct State(Arc<Mutex<StateInner>>);
 impl State {
     /// Intialize the IRC state from the given configuration.
     ///
-    /// `rehash` will be notified/pinged whenever an operator sends a REHASH command.
-    pub async fn new(config: config::State, rehash: Arc<Notify>) -> Self {
-        let inner = StateInner::new(config, rehash).await;
+    /// `rehash`, `die` and `restart` will be notified/pinged whenever an operator sends a REHASH,
+    /// DIE or RESTART command respectively.
+    pub async fn new(
+        config: config::State,
+        rehash: Arc<Notify>,
+        die: Arc<Notify>,
+        restart: Arc<Notify>,
+    ) -> Self {
+        let inner = StateInner::new(config, rehash, die, restart).await;
         Self(Arc::new(Mutex::new(inner)))
     }
 
@@ -70,13 +85,20 @@ impl State {
 
     /// Adds a new connection to the state.
     ///
-    /// The given `addr`ess is used to build the client's host, and the given `queue` is used to
-    /// push messages back to the client.
+    /// The given `peer` is used to build the client's host and find its connection class, the
+    /// given `queue` is used to push messages back to the client, and `sendq` keeps track of the
+    /// size of this queue.
     ///
     /// Each connection is identified by an integer.  This function returns the identifier for this
-    /// connection, which must be used to handle messages from this client.
-    pub async fn peer_joined(&self, addr: net::SocketAddr, queue: MessageQueue) -> usize {
-        self.0.lock().await.peer_joined(addr, queue)
+    /// connection, which must be used to handle messages from this client.  It returns `None` if
+    /// the connection is refused, in which case an ERROR message has been pushed to `queue`.
+    pub async fn peer_joined(
+        &self,
+        peer: Peer,
+        queue: MessageQueue,
+        sendq: Arc<SendQ>,
+    ) -> Option<usize> {
+        self.0.lock().await.peer_joined(peer, queue, sendq)
     }
 
     /// Removes the given connection from the state, with an optional error.
@@ -87,9 +109,35 @@ impl State {
         self.0.lock().await.peer_quit(id, err);
     }
 
-    /// Updates the state according to the given message from the given client.
-    pub async fn handle_message(&self, id: usize, msg: Message<'_>) -> u32 {
-        self.0.lock().await.handle_message(id, msg)
+    /// Updates the state according to the given message of `len` bytes from the given client.
+    ///
+    /// Returns the number of points the message costs, and the rate limit the client is subject
+    /// to, or `None` if the client is exempt from rate limits.
+    pub async fn handle_message(
+        &self,
+        id: usize,
+        msg: Message<'_>,
+        len: usize,
+    ) -> (u32, Option<config::RateLimit>) {
+        let mut inner = self.0.lock().await;
+        let verified = match inner.password_hashes(id, &msg) {
+            Some((hashes, password)) => {
+                // Password hashing is slow on purpose, don't block other clients meanwhile.
+                drop(inner);
+                let verify = move || {
+                    hashes
+                        .into_iter()
+                        .filter(|hash| password::verify(hash, &password))
+                        .collect()
+                };
+                let verified = task::spawn_blocking(verify).await.unwrap_or_default();
+                inner = self.0.lock().await;
+                verified
+            }
+            None => Vec::new(),
+        };
+        let points = inner.handle_message(id, msg, len, &verified);
+        (points, inner.rate_limit(id))
     }
 
     pub async fn remove_if_unregistered(&self, id: usize) {
@@ -100,6 +148,116 @@ impl State {
     pub async fn login_timeout(&self) -> u64 {
         self.0.lock().await.login_timeout
     }
+
+    /// Sets the bindings that are online, and whether they use TLS, for STATS P and for the STS
+    /// policy.
+    pub async fn set_bindings(&self, bindings: Vec<(config::BindAddress, bool)>) {
+        self.0.lock().await.set_bindings(bindings);
+    }
+
+    /// Disconnects all clients with the shutdown message, or with a message telling them to
+    /// reconnect if `restarting` is true.
+    ///
+    /// Returns the message queues of the clients, so that the caller can wait for them to be
+    /// sent.
+    pub async fn shutdown(&self, restarting: bool) -> Vec<Arc<SendQ>> {
+        self.0.lock().await.shutdown(restarting)
+    }
+
+    /// Sends a server notice with the given text to the operators that have selected its kind.
+    pub async fn send_snotice(&self, kind: snomask::Kind, text: impl fmt::Display) {
+        self.0.lock().await.send_snotice(kind, text);
+    }
+
+    /// Returns the clients connected to the server, for the admin socket.
+    pub async fn admin_clients(&self) -> serde_json::Value {
+        self.0.lock().await.admin_clients()
+    }
+
+    /// Returns the channels of the server, for the admin socket.
+    pub async fn admin_channels(&self) -> serde_json::Value {
+        self.0.lock().await.admin_channels()
+    }
+
+    /// Returns statistics about the server, for the admin socket.
+    pub async fn admin_stats(&self) -> serde_json::Value {
+        self.0.lock().await.admin_stats()
+    }
+
+    /// Returns the server bans and their reason.
+    pub async fn bans(&self) -> serde_json::Value {
+        self.0.lock().await.bans()
+    }
+
+    /// Writes the server bans to the bans file, if there is one.
+    pub async fn save_bans(&self) {
+        let (path, bans) = {
+            let inner = self.0.lock().await;
+            match &inner.bans_file {
+                Some(path) => (path.clone(), inner.bans.clone()),
+                None => return,
+            }
+        };
+        let _ = task::spawn_blocking(move || admin::save_bans(&path, &bans)).await;
+    }
+
+    /// Disconnects the client with the given nickname.  Returns whether it exists.
+    pub async fn kill(&self, nick: &str, reason: &str) -> bool {
+        self.0.lock().await.kill(nick, reason)
+    }
+
+    /// Bans the given mask from the server, and disconnects the clients that match it.
+    ///
+    /// Returns the number of disconnected clients.
+    pub async fn add_ban(&self, mask: String, reason: String) -> usize {
+        self.0.lock().await.add_ban(mask, reason)
+    }
+
+    /// Removes a server ban.  Returns whether it existed.
+    pub async fn remove_ban(&self, mask: &str) -> bool {
+        self.0.lock().await.remove_ban(mask)
+    }
+
+    /// Sends a NOTICE with the given text to all registered clients.
+    ///
+    /// Returns the number of clients the notice has been sent to.
+    pub async fn send_global_notice(&self, text: &str) -> usize {
+        self.0.lock().await.send_global_notice(text)
+    }
+
+    /// Returns all metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> String {
+        self.0.lock().await.metrics()
+    }
+
+    /// Appends an entry to the audit log, for an action that doesn't come from a client.
+    pub async fn audit(&self, event: &str, fields: serde_json::Value) {
+        self.0.lock().await.audit.write(event, fields);
+    }
+
+    /// Returns the rate limit the given client is subject to, or `None` if it is exempt.
+    pub async fn rate_limit(&self, id: usize) -> Option<config::RateLimit> {
+        self.0.lock().await.rate_limit(id)
+    }
+
+    /// Must be called each time the given client is slowed down by its rate limit.
+    pub async fn rate_limited(&self, id: usize) {
+        self.0.lock().await.rate_limited(id);
+    }
+
+    /// Must be called each time a TLS handshake fails.
+    pub async fn tls_handshake_failed(&self) {
+        self.0.lock().await.metrics.tls_handshake_failed();
+    }
+
+    /// Sends a PING to the given client if it has been inactive, or removes it if it didn't
+    /// answer the previous one.
+    ///
+    /// Returns the number of milliseconds to wait before the next check, or `None` if the client
+    /// is gone.
+    pub async fn check_ping(&self, id: usize) -> Option<u64> {
+        self.0.lock().await.check_ping(id)
+    }
 }
 
 /// The actual shared data (state) of the IRC server.
@@ -128,6 +286,9 @@ pub(crate) struct StateInner {
     /// register (in a "003 RPL_CREATED" reply).
     created_at: String,
 
+    /// When this instance was created, for STATS u.
+    started_at: Instant,
+
     /// The message of the day.
     motd: Option<String>,
 
@@ -140,6 +301,9 @@ pub(crate) struct StateInner {
     /// A list of (name, password) that are valid OPER parameters.
     opers: Vec<config::Oper>,
 
+    /// Privileges of operators, by class name.
+    oper_classes: Vec<config::OperClass>,
+
     /// Limits in number of characters for user input.
     awaylen: usize,
     channellen: usize,
@@ -153,12 +317,74 @@ pub(crate) struct StateInner {
     /// Registration timeout, in milliseconds.
     login_timeout: u64,
 
+    /// Flood control settings, see `config::RateLimit`.
+    rate_limit: config::RateLimit,
+
+    /// Number of points each command costs, when it differs from `Request::points`.
+    command_costs: HashMap<UniCase<String>, u32>,
+
+    /// Clients connecting from these addresses are not rate limited.
+    rate_limit_exempt: Vec<util::Cidr>,
+
+    /// Connection classes, see `config::Class`.
+    classes: Vec<config::Class>,
+
+    /// Default maximum size of the message queues, when not set by the client's class.
+    sendq: usize,
+
+    /// Default inactivity time before a PING is sent, in milliseconds.
+    ping_frequency: u64,
+
+    /// Secret used to compute cloaked hosts.
+    cloak_key: String,
+
+    /// Gateways allowed to use the WEBIRC command.
+    webirc: Vec<config::WebIrc>,
+
+    /// The STS policy, see `config::Sts`.
+    sts: Option<config::Sts>,
+
+    /// The bindings that are online, and whether they use TLS.
+    bindings: Vec<(config::BindAddress, bool)>,
+
+    /// Ports of the TLS bindings that are online.
+    tls_ports: Vec<u16>,
+
+    /// Message sent to clients when the server shuts down.
+    shutdown_message: String,
+
+    /// Server bans, added through the admin socket: masks and their reason.
+    bans: HashMap<String, String>,
+
+    /// Where `bans` are saved, see `State::save_bans`.  Only read at startup.
+    bans_file: Option<PathBuf>,
+
+    /// Counters exported by the metrics binding.
+    metrics: metrics::Metrics,
+
+    /// Where privileged actions are recorded.
+    audit: audit::Log,
+
+    /// Failed authentication attempts, see `lockout::Lockout`.
+    lockout: lockout::Lockout,
+
     /// Channel to send rehash notifications
     rehash: Arc<Notify>,
+
+    /// Channel to send shutdown notifications
+    die: Arc<Notify>,
+
+    /// Channel to send restart notifications
+    restart: Arc<Notify>,
 }
 
 impl StateInner {
-    pub async fn new(config: config::State, rehash: Arc<Notify>) -> Self {
+    pub async fn new(
+        config: config::State,
+        rehash: Arc<Notify>,
+        die: Arc<Notify>,
+        restart: Arc<Notify>,
+    ) -> Self {
         log::info!("Loading MOTD from {:?}", config.motd_file);
         let motd = match fs::read_to_string(&config.motd_file) {
             Ok(motd) => Some(motd),
@@ -176,10 +402,12 @@ impl StateInner {
             nicks: HashMap::new(),
             channels: HashMap::new(),
             created_at: util::time_str(),
+            started_at: Instant::now(),
             motd,
             password: config.password,
             default_chan_mode: config.default_chan_mode,
             opers: config.opers,
+            oper_classes: config.oper_classes,
             awaylen: config.awaylen,
             channellen: config.channellen,
             keylen: config.keylen,
@@ -189,11 +417,41 @@ impl StateInner {
             topiclen: config.topiclen,
             userlen: config.userlen,
             login_timeout: config.login_timeout,
+            rate_limit: config.rate_limit,
+            command_costs: command_costs(config.command_costs),
+            rate_limit_exempt: config.rate_limit_exempt,
+            classes: config.classes,
+            sendq: config.sendq,
+            ping_frequency: config.ping_frequency,
+            cloak_key: if config.cloak_key.is_empty() {
+                util::new_cloak_key()
+            } else {
+                config.cloak_key
+            },
+            webirc: config.webirc,
+            sts: config.sts,
+            bindings: Vec::new(),
+            tls_ports: Vec::new(),
+            shutdown_message: config.shutdown_message,
+            bans: admin::load_bans(config.bans_file.as_deref()),
+            bans_file: config.bans_file,
+            metrics: metrics::Metrics::default(),
+            audit: audit::Log::open(config.audit_log.as_deref()),
+            lockout: lockout::Lockout::new(config.auth_lockout),
             rehash,
+            die,
+            restart,
         }
     }
 
     pub fn rehash(&mut self, config: config::State) {
+        let old_caps = [self.config_caps(false), self.config_caps(true)];
+        let old_classes: HashMap<usize, String> = self
+            .clients
+            .iter()
+            .filter_map(|(id, client)| Some((id, self.classes[client.class?].name.clone())))
+            .collect();
+
         self.domain = Arc::from(config.domain);
         self.org_name = config.org_name;
         self.org_location = config.org_location;
@@ -206,6 +464,7 @@ impl StateInner {
         self.password = config.password;
         self.default_chan_mode = config.default_chan_mode;
         self.opers = config.opers;
+        self.oper_classes = config.oper_classes;
         self.awaylen = config.awaylen;
         self.channellen = config.channellen;
         self.keylen = config.keylen;
@@ -214,16 +473,237 @@ impl StateInner {
         self.topiclen = config.topiclen;
         self.userlen = config.userlen;
         self.login_timeout = config.login_timeout;
+        self.rate_limit = config.rate_limit;
+        self.command_costs = command_costs(config.command_costs);
+        self.rate_limit_exempt = config.rate_limit_exempt;
+        self.classes = config.classes;
+        self.sendq = config.sendq;
+        self.ping_frequency = config.ping_frequency;
+        if !config.cloak_key.is_empty() {
+            self.cloak_key = config.cloak_key;
+        }
+        self.webirc = config.webirc;
+        self.sts = config.sts;
+        self.shutdown_message = config.shutdown_message;
+        if self.audit.path() != config.audit_log.as_deref() {
+            self.audit = audit::Log::open(config.audit_log.as_deref());
+        }
+        self.lockout.rehash(config.auth_lockout);
+
+        // Class indices may have changed, so clients are put back in their class one by one, and
+        // the limits of the new classes are checked as if they were connecting.
+        let clients: Vec<usize> = self.clients.iter().map(|(id, _)| id).collect();
+        for id in &clients {
+            self.clients[*id].class = None;
+        }
+        for id in clients {
+            let client = &self.clients[id];
+            let class = self.find_class(client.ip(), client.tls(), client.binding(), client.account().is_some());
+            let class_name = class.map(|class| &self.classes[class].name);
+            let mut allowed = self.check_class(class, client.ip());
+            if client.is_registered() && class_name != old_classes.get(&id) && !self.has_password(id, class) {
+                allowed = Err(lines::BAD_PASSWORD);
+            }
+            if let Err(reason) = allowed {
+                log::debug!(client = id; "{}: Disconnected on rehash: {}", id, reason);
+                self.remove_client(id, reason, reason);
+                continue;
+            }
+            let sendq = self.class_sendq(class);
+            let client = &mut self.clients[id];
+            client.class = class;
+            client.set_sendq_max(sendq);
+            if client.operator {
+                let class = client.oper_class.clone();
+                let (privileges, allowed_snomask) = self.oper_privileges(class.as_deref());
+                let client = &mut self.clients[id];
+                client.privileges = privileges;
+                client.allowed_snomask = allowed_snomask;
+                client.snomask = client.snomask.intersection(allowed_snomask);
+            }
+            self.update_host(id);
+        }
+
+        self.notify_caps(&old_caps);
+    }
+
+    pub fn set_bindings(&mut self, bindings: Vec<(config::BindAddress, bool)>) {
+        let old_caps = [self.config_caps(false), self.config_caps(true)];
+        self.tls_ports = bindings
+            .iter()
+            .filter(|(_, tls)| *tls)
+            .filter_map(|(address, _)| address.port())
+            .collect();
+        self.bindings = bindings;
+        self.notify_caps(&old_caps);
+    }
+
+    pub fn peer_joined(&mut self, peer: Peer, queue: MessageQueue, sendq: Arc<SendQ>) -> Option<usize> {
+        let ip = peer.addr.ip();
+        let class = self.find_class(ip, peer.tls, &peer.binding, false);
+        if let Err(reason) = self.check_class(class, ip) {
+            log::info!(peer:% = peer.addr; "{}: Rejected: {}", peer.addr, reason);
+            self.send_snotice(snomask::Kind::Connect, format_args!("Connection from {} refused: {}", ip, reason));
+            let mut error = Buffer::new();
+            error.message("", "ERROR").trailing_param(reason);
+            let _ = queue.send(MessageQueueItem::from(error));
+            return None;
+        }
+
+        let addr = peer.addr;
+        self.metrics.connection_accepted(peer.binding.to_string());
+        let mut client = Client::new(self.domain.clone(), queue, sendq, peer);
+        client.class = class;
+        client.set_sendq_max(self.class_sendq(class));
+        if self.is_cloaked(class) {
+            client.set_host(&util::cloak(&self.cloak_key, ip));
+        }
+        let id = self.clients.insert(client);
+        log::debug!(client = id, peer:% = addr; "{}: Connected", addr);
+        Some(id)
+    }
+
+    /// Returns the index of the first class that matches the given connection.
+    fn find_class(&self, ip: net::IpAddr, tls: bool, binding: &config::BindAddress, logged_in: bool) -> Option<usize> {
+        self.classes.iter().position(|class| class.matches(ip, tls, binding, logged_in))
+    }
+
+    /// Returns an error if a new client from `ip` cannot join the given class.
+    fn check_class(&self, class: Option<usize>, ip: net::IpAddr) -> Result<(), &'static str> {
+        let class_idx = match class {
+            Some(class_idx) => class_idx,
+            None if self.classes.is_empty() => return Ok(()),
+            None => return Err(lines::NO_CLASS),
+        };
+        let class = &self.classes[class_idx];
+        let members = || self.clients.iter().filter(|(_, c)| c.class == Some(class_idx));
+
+        if let Some(max) = class.max_clients {
+            if max <= members().count() {
+                return Err(lines::CLASS_FULL);
+            }
+        }
+        if let Some(max) = class.max_clients_per_ip {
+            if max <= members().filter(|(_, c)| c.ip() == ip).count() {
+                return Err(lines::TOO_MANY_CONNECTIONS);
+            }
+        }
+        Ok(())
     }
 
-    pub fn peer_joined(&mut self, addr: net::SocketAddr, queue: MessageQueue) -> usize {
-        log::debug!("{}: Connected", addr);
-        let client = Client::new(self.domain.clone(), queue, addr.ip().to_string());
-        self.clients.insert(client)
+    /// Whether the given client has given the password of `class` with PASS, or if the class
+    /// has no password.
+    fn has_password(&self, id: usize, class: Option<usize>) -> bool {
+        let stored = class
+            .and_then(|class| self.classes[class].password.as_deref())
+            .unwrap_or(&self.password);
+        stored.is_empty() || self.clients[id].given_password.as_deref() == Some(stored)
+    }
+
+    /// Returns an error if the given registered client cannot move to `class`.
+    fn check_class_change(&self, id: usize, class: Option<usize>) -> Result<(), &'static str> {
+        self.check_class(class, self.clients[id].ip())?;
+        if !self.has_password(id, class) {
+            return Err(lines::BAD_PASSWORD);
+        }
+        Ok(())
+    }
+
+    /// Moves the given client to the class that matches its connection, now that it may be
+    /// logged in, and sets its host accordingly.  Returns an error if the new class is full, or
+    /// if the client is registered and has not given the password of the new class.
+    ///
+    /// The new host is not sent to anyone, so this must be called before the client is welcomed.
+    fn update_class(&mut self, id: usize) -> Result<(), &'static str> {
+        let client = &self.clients[id];
+        let ip = client.ip();
+        let class = self.find_class(ip, client.tls(), client.binding(), client.account().is_some());
+        if class != client.class {
+            if client.is_registered() {
+                self.check_class_change(id, class)?;
+            } else {
+                self.check_class(class, ip)?;
+            }
+            let sendq = self.class_sendq(class);
+            let client = &mut self.clients[id];
+            client.class = class;
+            client.set_sendq_max(sendq);
+        }
+
+        let host = self.displayed_host(id);
+        self.clients[id].set_host(&host);
+        Ok(())
+    }
+
+    /// Returns the host the given client is shown with: its vhost, its cloaked host if its class
+    /// is cloaked, or its real host.
+    fn displayed_host(&self, id: usize) -> String {
+        let client = &self.clients[id];
+        if let Some(vhost) = &client.vhost {
+            vhost.clone()
+        } else if self.is_cloaked(client.class) {
+            util::cloak(&self.cloak_key, client.ip())
+        } else {
+            client.real_host().to_owned()
+        }
+    }
+
+    /// The privileges and server notices given to operators of the given `oper_classes` entry, or
+    /// to operators without a class.
+    fn oper_privileges(&self, class: Option<&str>) -> (Vec<Privilege>, Snomask) {
+        match class {
+            None => (Privilege::ALL.to_vec(), Snomask::all()),
+            Some(name) => match self.oper_classes.iter().find(|class| class.name == *name) {
+                Some(class) => {
+                    let snomask = class.snomasks.as_deref().map_or_else(Snomask::all, Snomask::from_letters);
+                    (class.privileges.clone(), snomask)
+                }
+                None => (Vec::new(), Snomask::default()),
+            },
+        }
+    }
+
+    /// Updates the host of the given client after its class or the cloak key has changed, and
+    /// tells the other clients about it.
+    fn update_host(&mut self, id: usize) {
+        let host = self.displayed_host(id);
+        let client = &self.clients[id];
+        if host == client.host() {
+            return;
+        }
+        if !client.is_registered() {
+            self.clients[id].set_host(&host);
+            return;
+        }
+
+        let user = client.user().to_owned();
+        let mut rb = client.reply("");
+        self.change_host(id, &mut rb, &user, &host);
+        self.clients[id].send(rb);
+    }
+
+    /// Whether the hosts of clients in the given class are cloaked.
+    fn is_cloaked(&self, class: Option<usize>) -> bool {
+        match class {
+            Some(class) => self.classes[class].cloak,
+            None => false,
+        }
+    }
+
+    fn class_sendq(&self, class: Option<usize>) -> usize {
+        class.and_then(|class| self.classes[class].sendq).unwrap_or(self.sendq)
+    }
+
+    /// Returns the password the given client must give with PASS, or an empty string.
+    fn password(&self, id: usize) -> &str {
+        self.clients[id]
+            .class
+            .and_then(|class| self.classes[class].password.as_deref())
+            .unwrap_or(&self.password)
     }
 
     pub fn peer_quit(&mut self, id: usize, err: Option<impl fmt::Display>) {
-        log::debug!("{}: Disconnected", id);
+        log::debug!(client = id; "{}: Disconnected", id);
 
         if let Some(err) = err {
             self.remove_client(id, format_args!("{}", err), format_args!("{}", err));
@@ -249,6 +729,12 @@ impl StateInner {
         self.nicks.remove(u(client.nick()));
 
         if client.is_registered() {
+            self.send_snotice(snomask::Kind::Quit, format_args!(
+                "Client exiting: {} [{}] ({})",
+                client.full_name(),
+                client.ip(),
+                msg_to_others,
+            ));
             let mut quit_notice = Buffer::new();
             quit_notice.message(client.full_name(), Command::Quit).fmt_trailing_param(msg_to_others);
 
@@ -267,11 +753,77 @@ impl StateInner {
         client.send(error);
     }
 
-    pub fn handle_message(&mut self, id: usize, msg: Message<'_>) -> u32 {
-        let client = match self.clients.get(id) {
+    /// Returns the stored passwords the given message must be checked against, along with the
+    /// password it contains, or `None` if it doesn't contain any password worth checking.
+    ///
+    /// Nothing is hashed for locked out clients, the command handler then refuses the attempt.
+    fn password_hashes(&self, id: usize, msg: &Message<'_>) -> Option<(Vec<String>, String)> {
+        let client = self.clients.get(id)?;
+        let now = Instant::now();
+        let ip_key = self.lockout.ip_key(client.ip());
+        match Request::new(msg).ok()? {
+            Request::Oper(args) => {
+                let keys = [ip_key, lockout::Key::Oper(args.name.to_owned())];
+                if self.lockout.check(&keys, now).is_some() {
+                    return None;
+                }
+                let hashes = self.opers.iter().filter(|o| o.name == args.name);
+                Some((hashes.map(|o| o.password.clone()).collect(), args.password.to_owned()))
+            }
+            Request::Pass(password) => {
+                let stored = self.password(id);
+                if stored.is_empty() || self.lockout.check(&[ip_key], now).is_some() {
+                    return None;
+                }
+                Some((vec![stored.to_owned()], password.to_owned()))
+            }
+            Request::WebIrc(args) => {
+                if client.gateway.is_some() || client.is_registered() {
+                    return None;
+                }
+                let gateway_ip = client.ip();
+                let hashes = self
+                    .webirc
+                    .iter()
+                    .filter(|gateway| gateway.hosts.iter().any(|cidr| cidr.contains(gateway_ip)));
+                Some((hashes.map(|gateway| gateway.password.clone()).collect(), args.password.to_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a message of `len` bytes from the given client.
+    ///
+    /// `verified` contains the stored passwords that match the password in the message, see
+    /// `password_hashes`.
+    pub fn handle_message(
+        &mut self,
+        id: usize,
+        msg: Message<'_>,
+        len: usize,
+        verified: &[String],
+    ) -> u32 {
+        let start = Instant::now();
+        let command = match msg.command {
+            Ok(command) => command.as_str(),
+            Err(_) => "unknown",
+        };
+        if let Some(client) = self.clients.get_mut(id) {
+            client.recv_msgs += 1;
+            client.recv_bytes += len;
+        }
+        let points = self.do_handle_message(id, msg, verified);
+        self.metrics.message_handled(command, len, start.elapsed());
+        points
+    }
+
+    fn do_handle_message(&mut self, id: usize, msg: Message<'_>, verified: &[String]) -> u32 {
+        let client = match self.clients.get_mut(id) {
             Some(client) => client,
             None => return 999_999,
         };
+        client.last_seen = Instant::now();
+        let client = &self.clients[id];
 
         let label = msg.tags()
             .find(|tag| tag.key == "label")
@@ -280,7 +832,6 @@ impl StateInner {
             .unwrap_or("");
 
         let mut rb = client.reply(label);
-        let is_operator = client.operator;
 
         if MAX_TAG_DATA_LENGTH < msg.tags.len() {
             rb.reply(rpl::ERR_INPUTTOOLONG).trailing_param(lines::INPUT_TOO_LONG);
@@ -344,6 +895,13 @@ impl StateInner {
         };
 
         if !client.can_issue_request(&req) {
+            if let Request::WebIrc(_) = req {
+                if !client.is_registered() {
+                    log::debug!("{}:     WEBIRC sent too late", id);
+                    self.remove_client(id, lines::WEBIRC_TOO_LATE, "");
+                    return 2;
+                }
+            }
             if client.is_registered() {
                 rb.reply(rpl::ERR_ALREADYREGISTRED).trailing_param(lines::ALREADY_REGISTERED);
             } else {
@@ -353,20 +911,27 @@ impl StateInner {
             return 2;
         }
 
-        let points = req.points();
+        let points = msg
+            .command
+            .ok()
+            .and_then(|command| self.command_costs.get(u(command.as_str())))
+            .cloned()
+            .unwrap_or_else(|| req.points());
         let ctx = CommandContext {
             id,
             rb: &mut rb,
             client_tags: msg.tags,
         };
 
-        log::debug!("{}: {:?}", id, req);
+        let command = msg.command.ok().map_or("", |command| command.as_str());
+        log::debug!(client = id, peer:% = client.ip(), command = command; "{}: {:?}", id, req);
         let res = match req.clone() {
             // Requests about general server info.
             Request::Admin => self.cmd_admin(ctx),
             Request::Info => self.cmd_info(ctx),
             Request::LUsers => self.cmd_lusers(ctx),
             Request::Motd => self.cmd_motd(ctx),
+            Request::Stats(query) => self.cmd_stats(ctx, query),
             Request::Time => self.cmd_time(ctx),
             Request::Version => self.cmd_version(ctx),
             Request::WhoChannel(args) => self.cmd_who_channel(ctx, args),
@@ -376,9 +941,16 @@ impl StateInner {
             Request::WhoIs(args) => self.cmd_whois(ctx, args),
 
             // IRCop restricted requests.
+            Request::ChgHost(args) => self.cmd_chghost(ctx, args),
+            Request::Die => self.cmd_die(ctx),
             Request::Kill(args) => self.cmd_kill(ctx, args),
-            Request::Oper(args) => self.cmd_oper(ctx, args),
+            Request::Oper(args) => self.cmd_oper(ctx, args, verified),
             Request::Rehash => self.cmd_rehash(ctx),
+            Request::Restart => self.cmd_restart(ctx),
+            Request::SaJoin(args) => self.cmd_sajoin(ctx, args),
+            Request::SaMode(args) => self.cmd_samode(ctx, args),
+            Request::SaNick(args) => self.cmd_sanick(ctx, args),
+            Request::SaPart(args) => self.cmd_sapart(ctx, args),
 
             // Requests about channel info.
             Request::List(args) => self.cmd_list(ctx, args),
@@ -394,11 +966,12 @@ impl StateInner {
             Request::CapList => self.cmd_cap_list(ctx),
             Request::CapReq(args) => self.cmd_cap_req(ctx, args),
             Request::CapEnd => self.cmd_cap_end(ctx),
-            Request::Pass(args) => self.cmd_pass(ctx, args),
+            Request::Pass(_) => self.cmd_pass(ctx, verified),
             Request::Ping(args) => self.cmd_ping(ctx, args),
             Request::Pong(args) => self.cmd_pong(ctx, args),
             Request::Quit(args) => self.cmd_quit(ctx, args),
             Request::User(args) => self.cmd_user(ctx, args),
+            Request::WebIrc(args) => self.cmd_webirc(ctx, args, verified),
 
             // Client info related requests.
             Request::Away(args) => self.cmd_away(ctx, args),
@@ -431,10 +1004,42 @@ impl StateInner {
             let new_state = client.apply_request(&req);
 
             if new_state.is_registered() && !old_state.is_registered() {
-                log::debug!("{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
+                log::debug!(client = id; "{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
+                if let Err(reason) = self.update_class(id) {
+                    log::debug!(client = id; "{}:     Rejected: {}", id, reason);
+                    let client = &self.clients[id];
+                    self.send_snotice(snomask::Kind::Connect, format_args!(
+                        "Client {} [{}] refused: {}",
+                        client.full_name(),
+                        client.ip(),
+                        reason,
+                    ));
+                    self.remove_client(id, reason, "");
+                    return 999_999;
+                }
+                if let Some(reason) = self.ban_reason(id) {
+                    log::debug!(client = id; "{}:     Banned: {}", id, reason);
+                    let client = &self.clients[id];
+                    self.send_snotice(snomask::Kind::Ban, format_args!(
+                        "Client {} [{}] is banned: {}",
+                        client.full_name(),
+                        client.ip(),
+                        reason,
+                    ));
+                    let reason = format!("Banned: {}", reason);
+                    self.remove_client(id, reason, "");
+                    return 999_999;
+                }
+                let client = &self.clients[id];
+                self.send_snotice(snomask::Kind::Connect, format_args!(
+                    "Client connecting: {} [{}] ({})",
+                    client.full_name(),
+                    client.ip(),
+                    client.binding(),
+                ));
                 self.send_welcome(id, &mut rb);
             } else if !old_state.is_registered() {
-                log::debug!("{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
+                log::debug!(client = id; "{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
             }
 
             points
@@ -447,7 +1052,167 @@ impl StateInner {
             self.clients[id].send(rb);
         }
 
-        if is_operator { 1 } else { used_points }
+        used_points
+    }
+
+    /// Returns the rate limit that applies to the given client, or `None` if it is exempt.
+    ///
+    /// Operators with the bypass-ratelimit privilege, local clients and clients connecting from
+    /// `rate_limit_exempt` are exempt.
+    pub fn rate_limit(&self, id: usize) -> Option<config::RateLimit> {
+        let client = self.clients.get(id)?;
+        let ip = client.ip();
+        if client.has_privilege(config::Privilege::BypassRatelimit)
+            || client.local
+            || self.rate_limit_exempt.iter().any(|cidr| cidr.contains(ip))
+        {
+            return None;
+        }
+        let class_limit = client.class.and_then(|class| self.classes[class].rate_limit);
+        Some(class_limit.unwrap_or(self.rate_limit))
+    }
+
+    /// Returns the value of the `sts` capability for a client, or `None` if there is no STS
+    /// policy or if its TLS binding is not online.
+    pub fn sts_value(&self, tls: bool) -> Option<String> {
+        let sts = self.sts.as_ref()?;
+        let port = match sts.port {
+            Some(port) => self.tls_ports.iter().find(|p| **p == port)?,
+            None => self.tls_ports.first()?,
+        };
+        Some(if !tls {
+            format!("port={},duration={}", port, sts.duration)
+        } else if sts.preload {
+            format!("duration={},preload", sts.duration)
+        } else {
+            format!("duration={}", sts.duration)
+        })
+    }
+
+    pub fn shutdown(&mut self, restarting: bool) -> Vec<Arc<SendQ>> {
+        let text = if restarting { lines::RESTARTING } else { &self.shutdown_message };
+        let mut sendqs = Vec::with_capacity(self.clients.len());
+        for client in self.clients.drain() {
+            let mut msg = Buffer::new();
+            msg.message(&self.domain, Command::Notice)
+                .param(client.nick())
+                .trailing_param(text);
+            msg.message("", "ERROR").trailing_param(text);
+            client.send(msg);
+            sendqs.push(client.sendq());
+        }
+        self.nicks.clear();
+        self.channels.clear();
+        sendqs
+    }
+
+    pub fn metrics(&self) -> String {
+        let mut clients_per_binding = HashMap::new();
+        let mut sendq_bytes = 0;
+        let mut sendq_max_bytes = 0;
+        for (_, client) in &self.clients {
+            *clients_per_binding.entry(client.binding().to_string()).or_insert(0) += 1;
+            let len = client.sendq().len();
+            sendq_bytes += len;
+            sendq_max_bytes = sendq_max_bytes.max(len);
+        }
+        metrics::render(&metrics::Gauges {
+            clients: self.clients.len(),
+            registered: self.clients.iter().filter(|(_, c)| c.is_registered()).count(),
+            channels: self.channels.len(),
+            clients_per_binding,
+            sendq_bytes,
+            sendq_max_bytes,
+            metrics: &self.metrics,
+        })
+    }
+
+    /// Appends an entry to the audit log, for an action of the given client.
+    fn audit(&self, id: usize, event: &str, mut fields: serde_json::Value) {
+        let client = &self.clients[id];
+        fields["client"] = serde_json::Value::from(id);
+        fields["by"] = serde_json::Value::from(client.full_name());
+        self.audit.write(event, fields);
+    }
+
+    pub fn send_snotice(&self, kind: snomask::Kind, text: impl fmt::Display) {
+        let text = format!("*** {}: {}", kind.name(), text);
+        let receives = |c: &Client| c.operator && c.snomask.contains(kind);
+        for (_, client) in self.clients.iter().filter(|(_, c)| receives(c)) {
+            let mut notice = Buffer::new();
+            notice
+                .message(&self.domain, Command::Notice)
+                .param(client.nick())
+                .trailing_param(&text);
+            client.send(notice);
+        }
+    }
+
+    /// Records a failed OPER or PASS attempt of the given client, for its address and the given
+    /// operator name.  Names that are not in `opers` are ignored, so that they don't fill the
+    /// lockout table.
+    ///
+    /// Disconnects the client when it has made too many failed attempts.
+    fn auth_failed(&mut self, id: usize, oper: Option<&str>) {
+        let client = &self.clients[id];
+        let mut keys = vec![self.lockout.ip_key(client.ip())];
+        if let Some(name) = oper.filter(|name| self.opers.iter().any(|o| o.name == *name)) {
+            keys.push(lockout::Key::Oper(name.to_owned()));
+        }
+        for key in self.lockout.failed(&keys, Instant::now()) {
+            log::info!(client = id; "{}: {} locked out after too many failed attempts", id, key);
+            self.audit(id, "lockout", serde_json::json!({ "key": key.to_string() }));
+            self.send_snotice(snomask::Kind::Auth, format_args!(
+                "{} locked out after too many failed attempts, last by {} [{}]",
+                key,
+                client.full_name(),
+                client.ip(),
+            ));
+        }
+
+        let max_failures = self.lockout.max_failures();
+        let client = &mut self.clients[id];
+        client.auth_failures += 1;
+        if max_failures != 0 && max_failures <= client.auth_failures {
+            self.remove_client(id, lines::TOO_MANY_FAILURES, lines::TOO_MANY_FAILURES);
+        }
+    }
+
+    pub fn rate_limited(&mut self, id: usize) {
+        self.metrics.rate_limited();
+        let client = match self.clients.get_mut(id) {
+            Some(client) if !client.flooding => client,
+            _ => return,
+        };
+        client.flooding = true;
+        let client = &self.clients[id];
+        self.send_snotice(snomask::Kind::Flood, format_args!(
+            "{} [{}] hit its rate limit",
+            client.full_name(),
+            client.ip(),
+        ));
+    }
+
+    pub fn check_ping(&mut self, id: usize) -> Option<u64> {
+        let client = self.clients.get(id)?;
+        let frequency = client
+            .class
+            .and_then(|class| self.classes[class].ping_frequency)
+            .unwrap_or(self.ping_frequency)
+            .max(1000);
+        let elapsed = client.last_seen.elapsed().as_millis();
+
+        if u128::from(frequency) * 2 <= elapsed {
+            log::debug!(client = id; "{}: Ping timeout", id);
+            self.remove_client(id, lines::PING_TIMEOUT, lines::PING_TIMEOUT);
+            return None;
+        }
+        if u128::from(frequency) <= elapsed {
+            let mut ping = Buffer::new();
+            ping.message(&self.domain, Command::Ping).trailing_param(&self.domain);
+            client.send(ping);
+        }
+        Some(frequency)
     }
 
     pub fn remove_if_unregistered(&mut self, id: usize) {
@@ -459,6 +1224,14 @@ impl StateInner {
     }
 }
 
+/// Builds the table of command costs from the configuration.  Names are case-insensitive.
+fn command_costs(costs: HashMap<String, u32>) -> HashMap<UniCase<String>, u32> {
+    costs
+        .into_iter()
+        .map(|(name, cost)| (UniCase::new(name), cost))
+        .collect()
+}
+
 /// Returns `Ok(channel)` when `name` is an existing channel name.  Otherwise returns `Err(())` and
 /// send an error to the client.
 fn find_channel<'a>(
@@ -547,6 +1320,87 @@ impl StateInner {
         }
     }
 
+    /// Changes the user and host of the given client.
+    ///
+    /// The client and those that share a channel with it are sent a CHGHOST message if they
+    /// support it.  Otherwise, the client is replied RPL_HOSTHIDDEN in `rb`, and the others see
+    /// it quit and join its channels again.
+    fn change_host(&mut self, id: usize, rb: &mut ReplyBuffer, user: &str, host: &str) {
+        let client = &mut self.clients[id];
+        let old_full_name = client.full_name().to_owned();
+        client.set_user(user);
+        client.set_host(host);
+        if !client.is_registered() {
+            return;
+        }
+        let client = &self.clients[id];
+
+        // The user is prefixed like in `Client::full_name`.
+        let mut chghost = Buffer::new();
+        chghost
+            .message(&old_full_name, Command::ChgHost)
+            .fmt_param(format_args!("~{}", user))
+            .param(host);
+        let chghost = MessageQueueItem::from(chghost);
+        if client.cap_enabled.chghost {
+            rb.message(&old_full_name, Command::ChgHost)
+                .fmt_param(format_args!("~{}", user))
+                .param(host);
+        } else {
+            rb.reply(rpl::HOSTHIDDEN)
+                .param(host)
+                .trailing_param(lines::HOST_HIDDEN);
+        }
+        self.send_notification(id, chghost, |_, c| c.cap_enabled.chghost);
+
+        let mut quit = Buffer::new();
+        quit.message(&old_full_name, Command::Quit)
+            .trailing_param(lines::CHANGING_HOST);
+        self.send_notification(id, quit, |_, c| !c.cap_enabled.chghost);
+
+        let channels = self.channels.iter().filter(|(_, c)| c.members.contains_key(&id));
+        for (name, channel) in channels {
+            let mut join = Buffer::new();
+            join.message(client.full_name(), Command::Join)
+                .param(name.get());
+            let mut extended_join = Buffer::new();
+            extended_join
+                .message(client.full_name(), Command::Join)
+                .param(name.get())
+                .param(client.account().unwrap_or("*"))
+                .trailing_param(client.real());
+
+            let mut letters = String::from("+");
+            channel.members[&id].all_letters(&mut letters);
+            if 1 < letters.len() {
+                let msg = join
+                    .message(&self.domain, Command::Mode)
+                    .param(name.get())
+                    .param(&letters);
+                (1..letters.len()).fold(msg, |msg, _| msg.param(client.nick()));
+                let msg = extended_join
+                    .message(&self.domain, Command::Mode)
+                    .param(name.get())
+                    .param(&letters);
+                (1..letters.len()).fold(msg, |msg, _| msg.param(client.nick()));
+            }
+
+            let join = MessageQueueItem::from(join);
+            let extended_join = MessageQueueItem::from(extended_join);
+            for member in channel.members.keys().filter(|m| **m != id) {
+                let member = &self.clients[*member];
+                if member.cap_enabled.chghost {
+                    continue;
+                }
+                if member.cap_enabled.extended_join {
+                    member.send(extended_join.clone());
+                } else {
+                    member.send(join.clone());
+                }
+            }
+        }
+    }
+
     fn send_i_support(&self, rb: &mut ReplyBuffer) {
         rb.reply(rpl::ISUPPORT)
             .param("CASEMAPPING=ascii")
@@ -1,9 +1,9 @@
 //! Testing utilities for `ellidri::state`
 
 use super::State;
-use crate::client::MessageQueueItem;
-use crate::{auth, config};
-use ellidri_tokens::{assert_msg, Command, Message};
+use crate::client::{MessageQueueItem, Peer};
+use crate::config;
+use ellidri_tokens::Message;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Notify};
@@ -11,74 +11,66 @@ use tokio::sync::{mpsc, Notify};
 pub type ClientId = usize;
 pub type Queue = mpsc::UnboundedReceiver<MessageQueueItem>;
 
-pub fn simple_state() -> State {
-    let config = config::State::sample();
-    let auth_provider = auth::choose_provider(config::SaslBackend::None, None).unwrap();
-    let rehash = Arc::new(Notify::new());
-    State::new(config, auth_provider, rehash)
+/// Returns a state with the sample configuration, no MOTD and an operator "admin" whose password
+/// is "admin".
+pub async fn simple_state() -> State {
+    let mut config = config::State::sample();
+    config.motd_file = String::new();
+    config.opers = vec![serde_yaml::from_str("{name: admin, password: admin}").unwrap()];
+    let notify = || Arc::new(Notify::new());
+    State::new(config, notify(), notify(), notify()).await
 }
 
 pub async fn add_client(s: &State) -> (ClientId, Queue) {
     let port = s.0.lock().await.clients.len() as u16;
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let peer = Peer {
+        addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        binding: config::BindAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], 6667))),
+        local: false,
+        tls: false,
+        tls_info: None,
+        certfp: None,
+    };
     let (msg_queue, outgoing_msgs) = mpsc::unbounded_channel();
-    let res = s.peer_joined(addr, msg_queue).await;
+    let res = s.peer_joined(peer, msg_queue, Arc::default()).await.unwrap();
     (res, outgoing_msgs)
 }
 
-pub async fn add_registered_client(s: &State, nickname: &str) -> (usize, Queue) {
-    let (id, queue) = add_client(s).await;
-    let nick = format!("NICK :{}", nickname);
-    let nick = Message::parse(&nick).unwrap();
-    let user = Message::parse("USER X X X X").unwrap();
-    let _ = s.handle_message(id, nick).await;
-    let _ = s.handle_message(id, user).await;
+/// Adds a registered client with the given nickname and capabilities.
+pub async fn add_registered_client(s: &State, nickname: &str, caps: &str) -> (ClientId, Queue) {
+    let (id, mut queue) = add_client(s).await;
+    if !caps.is_empty() {
+        handle_message(s, id, &format!("CAP REQ :{}", caps)).await;
+    }
+    handle_message(s, id, &format!("NICK {}", nickname)).await;
+    handle_message(s, id, &format!("USER {} 0 * :{}", nickname, nickname)).await;
+    handle_message(s, id, "CAP END").await;
+    flush(&mut queue);
     (id, queue)
 }
 
-pub async fn handle_message(state: &State, id: ClientId, message: &str) {
-    let message = Message::parse(message).unwrap();
-    let _ = state.handle_message(id, message).await;
-}
-
-pub fn flush(queue: &mut Queue) {
-    loop {
-        match queue.try_recv() {
-            Ok(_msg) => {
-                //println!("flushed: {:?}", msg);
-            }
-            Err(mpsc::error::TryRecvError::Empty) => return,
-            Err(_) => unreachable!(),
-        }
-    }
+/// Adds a registered client named "admin" that is logged in as an operator.
+pub async fn add_oper(s: &State) -> (ClientId, Queue) {
+    let (id, mut queue) = add_registered_client(s, "admin", "").await;
+    handle_message(s, id, "OPER admin admin").await;
+    flush(&mut queue);
+    (id, queue)
 }
 
-pub fn collect(res: &mut String, queue: &mut Queue) {
-    loop {
-        match queue.try_recv() {
-            Ok(item) => {
-                let s: &str = item.as_ref();
-                res.push_str(s);
-            }
-            Err(mpsc::error::TryRecvError::Empty) => return,
-            Err(_) => unreachable!(),
-        }
-    }
+pub async fn handle_message(state: &State, id: ClientId, message: &str) {
+    let msg = Message::parse(message).unwrap();
+    let _ = state.handle_message(id, msg, message.len()).await;
 }
 
-pub fn messages(s: &str) -> impl Iterator<Item = Message<'_>> {
-    s.lines()
-        .map(|line| Message::parse(line).expect("bad message"))
+pub fn flush(queue: &mut Queue) {
+    while queue.try_recv().is_ok() {}
 }
 
-type ExpectedMessage<'a> = (Option<&'a str>, Result<Command, &'a str>, &'a [&'a str]);
-
-pub fn assert_msgs(s: &str, expected: &[ExpectedMessage<'_>]) {
-    let mut i = 0;
-    for msg in messages(s) {
-        let (prefix, command, params) = expected[i];
-        assert_msg(&msg, prefix, command, params);
-        i += 1;
+/// Returns the messages received by a client since the last call.
+pub fn collect(queue: &mut Queue) -> String {
+    let mut res = String::new();
+    while let Ok(item) = queue.try_recv() {
+        res.push_str(item.as_ref());
     }
-    assert_eq!(i, expected.len());
+    res
 }
@@ -6,9 +6,14 @@
 use super::{find_channel, find_member, find_nick, CommandContext, HandlerResult as Result};
 use crate::channel::{MemberModes, Topic};
 use crate::client::MessageQueueItem;
+use crate::config::Privilege;
+use crate::lockout::Key;
+use crate::snomask;
 use crate::{data, lines, util, Channel, Client};
 use ellidri_tokens::{mode, rpl, Buffer, Command, ReplyBuffer};
 use ellidri_unicase::{u, UniCase};
+use serde_json::json;
+use std::time::Instant;
 
 // Command handlers
 impl super::StateInner {
@@ -60,6 +65,23 @@ impl super::StateInner {
         Ok(())
     }
 
+    // DIE
+
+    pub fn cmd_die(&self, ctx: CommandContext<'_>) -> Result {
+        let client = &self.clients[ctx.id];
+        if client.has_privilege(Privilege::Die) {
+            log::info!("{}: {} asked the server to shut down", ctx.id, client.nick());
+            self.audit(ctx.id, "die", json!({}));
+            self.die.notify();
+            Ok(())
+        } else {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            Err(())
+        }
+    }
+
     // INFO
 
     pub fn cmd_info(&self, ctx: CommandContext<'_>) -> Result {
@@ -236,7 +258,13 @@ impl super::StateInner {
         }
     }
 
-    pub fn cmd_join(&mut self, mut ctx: CommandContext<'_>, list: data::JoinList<'_>) -> Result {
+    pub fn cmd_join(&mut self, ctx: CommandContext<'_>, list: data::JoinList<'_>) -> Result {
+        self.join(ctx, list, false)
+    }
+
+    /// Joins the given channels.  When `forced` (SAJOIN), keys, limits, invitations and bans are
+    /// not checked.
+    pub(super) fn join(&mut self, mut ctx: CommandContext<'_>, list: data::JoinList<'_>, forced: bool) -> Result {
         let client = &self.clients[ctx.id];
 
         let mut update_idle = false;
@@ -245,6 +273,9 @@ impl super::StateInner {
                 .channels
                 .get(u(channel_name.get()))
                 .map_or(Ok(()), |channel| {
+                    if forced && !channel.members.contains_key(&ctx.id) {
+                        return Ok(());
+                    }
                     Self::check_join(
                         client,
                         channel,
@@ -376,13 +407,23 @@ impl super::StateInner {
 
     pub fn cmd_kill(&mut self, ctx: CommandContext<'_>, args: data::req::Kill<'_>) -> Result {
         let client = &self.clients[ctx.id];
-        if !client.operator {
+        if !client.has_privilege(Privilege::Kill) {
             ctx.rb
                 .reply(rpl::ERR_NOPRIVILEDGES)
                 .trailing_param(lines::NO_PRIVILEDGES);
             return Err(());
         }
         let (target_id, _) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+        self.audit(ctx.id, "kill", json!({
+            "target": self.clients[target_id].full_name(),
+            "reason": args.reason,
+        }));
+        self.send_snotice(snomask::Kind::Kill, format_args!(
+            "{} killed by {}: {}",
+            self.clients[target_id].full_name(),
+            client.nick(),
+            args.reason,
+        ));
         self.remove_client(target_id, format_args!("Killed: {}", args.reason), "Killed");
         Ok(())
     }
@@ -390,11 +431,11 @@ impl super::StateInner {
     // LIST
 
     pub fn cmd_list_all(&self, ctx: CommandContext<'_>) -> Result {
-        let client = &self.clients[ctx.id];
+        let see_hidden = self.clients[ctx.id].has_privilege(Privilege::SeeHidden);
         ctx.rb.lr_batch_begin();
 
         for (name, channel) in &self.channels {
-            if channel.secret && !client.operator && !channel.members.contains_key(&ctx.id) {
+            if channel.secret && !see_hidden && !channel.members.contains_key(&ctx.id) {
                 continue;
             }
             let msg = ctx.rb.reply(rpl::LIST).param(name.get());
@@ -413,12 +454,12 @@ impl super::StateInner {
         ctx: CommandContext<'_>,
         targets: data::List<'_, data::ChannelName<'_>>,
     ) -> Result {
-        let client = &self.clients[ctx.id];
+        let see_hidden = self.clients[ctx.id].has_privilege(Privilege::SeeHidden);
         ctx.rb.lr_batch_begin();
 
         for name in targets.iter() {
             if let Some(channel) = self.channels.get(name.u()) {
-                if channel.secret && !client.operator && !channel.members.contains_key(&ctx.id) {
+                if channel.secret && !see_hidden && !channel.members.contains_key(&ctx.id) {
                     continue;
                 }
                 let msg = ctx.rb.reply(rpl::LIST).param(name.get());
@@ -449,7 +490,8 @@ impl super::StateInner {
         channel_name: data::ChannelName<'_>,
     ) -> Result {
         let channel = find_channel(ctx.id, ctx.rb, &self.channels, channel_name)?;
-        let full_info = channel.members.contains_key(&ctx.id) || self.clients[ctx.id].operator;
+        let full_info = channel.members.contains_key(&ctx.id)
+            || self.clients[ctx.id].has_privilege(Privilege::SeeHidden);
 
         let msg = ctx.rb.reply(rpl::CHANNELMODEIS).param(channel_name.get());
         channel.modes(msg, full_info);
@@ -458,9 +500,20 @@ impl super::StateInner {
     }
 
     pub fn cmd_mode_channel_set(
+        &mut self,
+        ctx: CommandContext<'_>,
+        args: data::req::ModeChannelSet<'_>,
+    ) -> Result {
+        self.mode_channel_set(ctx, args, false)
+    }
+
+    /// Changes the modes of a channel.  When `forced` (SAMODE), the issuer doesn't need to be on
+    /// the channel nor to be a channel operator.
+    pub(super) fn mode_channel_set(
         &mut self,
         mut ctx: CommandContext<'_>,
         args: data::req::ModeChannelSet<'_>,
+        forced: bool,
     ) -> Result {
         let channel = match self.channels.get_mut(args.channel.u()) {
             Some(channel) => channel,
@@ -475,15 +528,16 @@ impl super::StateInner {
         };
 
         let issuer = &self.clients[ctx.id];
-        let issuer_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
-
-        if !issuer.operator && !issuer_modes.can_change(args.modes) {
-            log::debug!("{}:     not operator", ctx.id);
-            ctx.rb
-                .reply(rpl::ERR_CHANOPRIVSNEEDED)
-                .param(args.channel.get())
-                .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
-            return Err(());
+        if !forced {
+            let issuer_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
+            if !issuer.has_privilege(Privilege::OverrideChannel) && !issuer_modes.can_change(args.modes) {
+                log::debug!("{}:     not operator", ctx.id);
+                ctx.rb
+                    .reply(rpl::ERR_CHANOPRIVSNEEDED)
+                    .param(args.channel.get())
+                    .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
+                return Err(());
+            }
         }
 
         let reply_list = |rb: &mut ReplyBuffer, item, end, line: &str, it: util::Masks<'_>| {
@@ -591,6 +645,14 @@ impl super::StateInner {
                 .param(args.channel.get())
                 .param(&applied_modes);
             applied_modeparams.iter().fold(msg, |msg, mp| msg.param(mp));
+
+            if issuer.operator {
+                self.audit(ctx.id, "mode", json!({
+                    "channel": args.channel.get(),
+                    "modes": applied_modes,
+                    "params": applied_modeparams,
+                }));
+            }
         }
 
         Ok(())
@@ -602,6 +664,7 @@ impl super::StateInner {
         args: data::req::ModeUserSet<'_>,
     ) -> Result {
         let client = &mut self.clients[ctx.id];
+        let oper_vhost = client.oper_vhost.clone();
 
         if u(client.nick()) != args.user.u() {
             log::debug!("{}:     users don't match", ctx.id);
@@ -613,13 +676,38 @@ impl super::StateInner {
         }
 
         let mut applied_modes = String::with_capacity(args.modes.len() + 1);
+        let mut snomask_changed = false;
+        let mut deopered = false;
         for maybe_change in args.modes.iter() {
             match maybe_change {
+                Ok(mode::UserChange::ServerNotices(true)) => {
+                    if !client.operator {
+                        ctx.rb
+                            .reply(rpl::ERR_NOPRIVILEDGES)
+                            .trailing_param(lines::NO_PRIVILEDGES);
+                        continue;
+                    }
+                    let was_set = !client.snomask.is_empty();
+                    let is_set = client.apply_snomask(args.snomask);
+                    log::debug!("  - Applied snomask {}", client.snomask);
+                    snomask_changed = true;
+                    if was_set != is_set {
+                        applied_modes.push(if is_set { '+' } else { '-' });
+                        applied_modes.push('s');
+                    }
+                }
                 Ok(change) => {
+                    let had_snomask = !client.snomask.is_empty();
                     if client.apply_mode_change(change) {
                         log::debug!("  - Applied {:?}", change);
                         applied_modes.push(if change.value() { '+' } else { '-' });
                         applied_modes.push(change.symbol());
+                        if change == mode::UserChange::DeOperator {
+                            deopered = true;
+                            if had_snomask {
+                                applied_modes.push('s');
+                            }
+                        }
                     }
                 }
                 Err(mode::Error::Unknown(mode, _)) => {
@@ -636,6 +724,20 @@ impl super::StateInner {
                 .param(args.user.get())
                 .param(&applied_modes);
         }
+        if snomask_changed && !client.snomask.is_empty() {
+            ctx.rb
+                .reply(rpl::SNOMASK)
+                .fmt_param(client.snomask)
+                .trailing_param(lines::SNOMASK);
+        }
+
+        // The host given by OPER is removed along with the privileges.
+        if deopered && oper_vhost.is_some() && client.vhost == oper_vhost {
+            client.vhost = None;
+            let user = client.user().to_owned();
+            let host = self.displayed_host(ctx.id);
+            self.change_host(ctx.id, ctx.rb, &user, &host);
+        }
 
         Ok(())
     }
@@ -732,31 +834,91 @@ impl super::StateInner {
             .message(issuer.full_name(), Command::Nick)
             .param(nick.get());
 
+        let old_full_name = issuer.full_name().to_owned();
         issuer.set_nick(nick.get());
         ReplyBuffer::set_nick(nick.get());
 
         self.send_notification(ctx.id, nick_response, |_, _| true);
+        self.send_snotice(snomask::Kind::Nick, format_args!(
+            "{} is now known as {}",
+            old_full_name,
+            nick.get(),
+        ));
 
         Ok(())
     }
 
     // OPER
 
-    pub fn cmd_oper(&mut self, ctx: CommandContext<'_>, args: data::req::Oper<'_>) -> Result {
-        if !self
-            .opers
-            .iter()
-            .any(|o| o.name == args.name && o.password == args.password)
-        {
-            log::debug!("{}:     Password mismatch", ctx.id);
+    pub fn cmd_oper(
+        &mut self,
+        ctx: CommandContext<'_>,
+        args: data::req::Oper<'_>,
+        verified: &[String],
+    ) -> Result {
+        let client = &self.clients[ctx.id];
+        let certfp = client.certfp();
+        let tls = client.tls();
+        let user_host = format!("{}@{}", client.user(), client.host());
+        let user_ip = format!("{}@{}", client.user(), client.ip());
+        let mut opers = self.opers.iter().filter(|o| {
+            o.name == args.name
+                && (o.certfp.is_none() || o.certfp.as_deref() == certfp)
+                && (!o.require_tls || tls)
+                && (o.hosts.is_empty()
+                    || o.hosts.iter().any(|mask| {
+                        util::match_mask(mask, &user_host) || util::match_mask(mask, &user_ip)
+                    }))
+        });
+
+        let keys = [self.lockout.ip_key(client.ip()), Key::Oper(args.name.to_owned())];
+        if self.lockout.check(&keys, Instant::now()).is_some() {
+            log::debug!("{}:     Locked out", ctx.id);
             ctx.rb
                 .reply(rpl::ERR_PASSWDMISMATCH)
-                .trailing_param(lines::PASSWORD_MISMATCH);
+                .trailing_param(lines::AUTH_LOCKED);
             return Err(());
         }
 
+        // Several blocks can share a name, the first one that accepts the password is used.
+        let oper = opers.find(|o| verified.contains(&o.password));
+        let oper = match oper {
+            Some(oper) => oper,
+            None => {
+                log::debug!("{}:     Password mismatch", ctx.id);
+                self.audit(ctx.id, "oper", json!({ "name": args.name, "success": false }));
+                self.send_snotice(snomask::Kind::Oper, format_args!(
+                    "Failed OPER attempt by {} [{}] ({})",
+                    client.full_name(),
+                    client.ip(),
+                    args.name,
+                ));
+                ctx.rb
+                    .reply(rpl::ERR_PASSWDMISMATCH)
+                    .trailing_param(lines::PASSWORD_MISMATCH);
+                self.auth_failed(ctx.id, Some(args.name));
+                return Err(());
+            }
+        };
+
+        let (privileges, allowed_snomask) = self.oper_privileges(oper.class.as_deref());
+        let class = oper.class.clone();
+        let vhost = oper.vhost.clone();
+        self.lockout.succeeded(&keys[1]);
+
+        self.audit(ctx.id, "oper", json!({ "name": args.name, "success": true }));
+        self.send_snotice(snomask::Kind::Oper, format_args!(
+            "{} [{}] is now an operator ({})",
+            client.full_name(),
+            client.ip(),
+            args.name,
+        ));
         let client = &mut self.clients[ctx.id];
         client.operator = true;
+        client.oper_class = class;
+        client.oper_vhost = vhost.clone();
+        client.privileges = privileges;
+        client.allowed_snomask = allowed_snomask;
 
         ctx.rb.lr_batch_begin();
         ctx.rb
@@ -766,6 +928,11 @@ impl super::StateInner {
         ctx.rb
             .reply(rpl::YOUREOPER)
             .trailing_param(lines::YOURE_OPER);
+        if let Some(vhost) = vhost {
+            let user = client.user().to_owned();
+            client.vhost = Some(vhost.clone());
+            self.change_host(ctx.id, ctx.rb, &user, &vhost);
+        }
 
         Ok(())
     }
@@ -873,12 +1040,30 @@ impl super::StateInner {
 
     // PASS
 
-    pub fn cmd_pass(&mut self, ctx: CommandContext<'_>, password: &str) -> Result {
-        if self.password == password {
-            self.clients[ctx.id].has_given_password = true;
+    pub fn cmd_pass(&mut self, ctx: CommandContext<'_>, verified: &[String]) -> Result {
+        let stored = self.password(ctx.id);
+        if stored.is_empty() {
+            return Ok(());
         }
 
-        Ok(())
+        let ip = self.clients[ctx.id].ip();
+        if self.lockout.check(&[self.lockout.ip_key(ip)], Instant::now()).is_some() {
+            log::debug!("{}:     Locked out", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_PASSWDMISMATCH)
+                .trailing_param(lines::AUTH_LOCKED);
+            return Err(());
+        }
+
+        if verified.iter().any(|hash| hash == stored) {
+            self.clients[ctx.id].given_password = Some(stored.to_owned());
+            Ok(())
+        } else {
+            log::debug!("{}:     Password mismatch", ctx.id);
+            self.send_snotice(snomask::Kind::Auth, format_args!("Wrong server password from [{}]", ip));
+            self.auth_failed(ctx.id, None);
+            Err(())
+        }
     }
 
     // PING
@@ -908,11 +1093,12 @@ impl super::StateInner {
     // REHASH
 
     pub fn cmd_rehash(&self, ctx: CommandContext<'_>) -> Result {
-        if self.clients[ctx.id].operator {
+        if self.clients[ctx.id].has_privilege(Privilege::Rehash) {
             ctx.rb
                 .reply(rpl::REHASHING)
                 .param("--")
                 .trailing_param(lines::REHASHING);
+            self.audit(ctx.id, "rehash", json!({}));
             self.rehash.notify();
             Ok(())
         } else {
@@ -923,6 +1109,133 @@ impl super::StateInner {
         }
     }
 
+    // RESTART
+
+    pub fn cmd_restart(&self, ctx: CommandContext<'_>) -> Result {
+        let client = &self.clients[ctx.id];
+        if client.has_privilege(Privilege::Die) {
+            log::info!("{}: {} asked the server to restart", ctx.id, client.nick());
+            self.audit(ctx.id, "restart", json!({}));
+            self.restart.notify();
+            Ok(())
+        } else {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            Err(())
+        }
+    }
+
+    // STATS
+
+    pub fn cmd_stats(&self, ctx: CommandContext<'_>, query: &str) -> Result {
+        let client = &self.clients[ctx.id];
+        let letter = query.chars().next().unwrap_or('?');
+        let allowed = match letter {
+            'u' | '?' => true,
+            'k' | 'd' => client.has_privilege(Privilege::Ban),
+            _ => client.has_privilege(Privilege::SeeHidden),
+        };
+        if !allowed {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            return Err(());
+        }
+
+        ctx.rb.lr_batch_begin();
+        match letter {
+            'u' => {
+                let secs = self.started_at.elapsed().as_secs();
+                ctx.rb
+                    .reply(rpl::STATSUPTIME)
+                    .fmt_trailing_param(lines_stats_uptime!(secs));
+            }
+            'm' => {
+                for (command, count, bytes) in self.metrics.messages() {
+                    ctx.rb
+                        .reply(rpl::STATSCOMMANDS)
+                        .param(command)
+                        .fmt_param(count)
+                        .fmt_param(bytes)
+                        .param("0");
+                }
+            }
+            'l' | 'L' => {
+                let now = util::time();
+                for (_, c) in &self.clients {
+                    let sendq = c.sendq();
+                    let (sent_msgs, sent_bytes) = sendq.sent_total();
+                    let host = if letter == 'l' { c.host().to_owned() } else { c.ip().to_string() };
+                    ctx.rb
+                        .reply(rpl::STATSLINKINFO)
+                        .fmt_param(format_args!("{}[{}@{}]", c.nick(), c.user(), host))
+                        .fmt_param(sendq.len())
+                        .fmt_param(sent_msgs)
+                        .fmt_param(sent_bytes / 1024)
+                        .fmt_param(c.recv_msgs)
+                        .fmt_param(c.recv_bytes / 1024)
+                        .fmt_param(now.saturating_sub(c.signon_time()));
+                }
+            }
+            'o' => {
+                for oper in &self.opers {
+                    let hosts = if oper.hosts.is_empty() { String::from("*") } else { oper.hosts.join(",") };
+                    ctx.rb
+                        .reply(rpl::STATSOLINE)
+                        .param("O")
+                        .param(&hosts)
+                        .param("*")
+                        .param(&oper.name)
+                        .param(oper.class.as_deref().unwrap_or("*"));
+                }
+            }
+            'k' => {
+                for (mask, reason) in &self.bans {
+                    ctx.rb
+                        .reply(rpl::STATSKLINE)
+                        .param("K")
+                        .param(mask)
+                        .param("*")
+                        .param("*")
+                        .trailing_param(reason);
+                }
+            }
+            'd' => {
+                for (mask, reason) in self.bans.iter().filter(|(mask, _)| mask.starts_with("*!*@")) {
+                    ctx.rb
+                        .reply(rpl::STATSDLINE)
+                        .param("D")
+                        .param(&mask[4..])
+                        .trailing_param(reason);
+                }
+            }
+            'P' => {
+                for (address, tls) in &self.bindings {
+                    let clients = self.clients.iter().filter(|(_, c)| c.binding() == address).count();
+                    ctx.rb
+                        .reply(rpl::STATSPLINE)
+                        .param("P")
+                        .fmt_param(address)
+                        .fmt_param(clients)
+                        .param(if *tls { "TLS" } else { "plain" });
+                }
+            }
+            '?' => {
+                for line in lines::STATS_HELP {
+                    ctx.rb.reply(rpl::STATSDEBUG).trailing_param(line);
+                }
+            }
+            _ => {}
+        }
+        ctx.rb
+            .reply(rpl::ENDOFSTATS)
+            .fmt_param(letter)
+            .trailing_param(lines::END_OF_STATS);
+
+        Ok(())
+    }
+
     // TIME
 
     pub fn cmd_time(&self, ctx: CommandContext<'_>) -> Result {
@@ -1015,9 +1328,7 @@ impl super::StateInner {
     // USER
 
     pub fn cmd_user(&mut self, ctx: CommandContext<'_>, args: data::req::User<'_>) -> Result {
-        let client = &mut self.clients[ctx.id];
-
-        if !self.password.is_empty() && !client.has_given_password {
+        if !self.has_password(ctx.id, self.clients[ctx.id].class) {
             log::debug!("{}:     Password mismatch", ctx.id);
             ctx.rb
                 .reply(rpl::ERR_PASSWDMISMATCH)
@@ -1026,6 +1337,7 @@ impl super::StateInner {
             return Err(());
         }
 
+        let client = &mut self.clients[ctx.id];
         client.set_user(&args.username[..args.username.len().min(self.userlen)]);
         client.set_real(&args.realname[..args.realname.len().min(self.namelen)]);
 
@@ -1102,7 +1414,7 @@ impl super::StateInner {
                 Some(member_modes) => *member_modes,
                 None => continue,
             };
-            if !issuer.operator
+            if !issuer.has_privilege(Privilege::SeeHidden)
                 && (target.invisible || channel.secret)
                 && !channel.members.contains_key(&issuer_id)
             {
@@ -1122,7 +1434,10 @@ impl super::StateInner {
 
             break;
         }
-        if !target.invisible || target_id == issuer_id || channel_name.is_some() || issuer.operator
+        if !target.invisible
+            || target_id == issuer_id
+            || channel_name.is_some()
+            || issuer.has_privilege(Privilege::SeeHidden)
         {
             // The client can see the target.
             let channel_name = channel_name.map_or("*", UniCase::get);
@@ -1132,7 +1447,7 @@ impl super::StateInner {
 
     pub fn cmd_who_all(&self, mut ctx: CommandContext<'_>, filter: data::req::WhoFilter) -> Result {
         let issuer = &self.clients[ctx.id];
-        if !issuer.operator {
+        if !issuer.has_privilege(Privilege::SeeHidden) {
             ctx.rb
                 .reply(rpl::ENDOFWHO)
                 .param("*")
@@ -1169,7 +1484,8 @@ impl super::StateInner {
             let issuer = &self.clients[ctx.id];
 
             let in_channel = channel.members.contains_key(&ctx.id);
-            if channel.secret && !in_channel && !issuer.operator {
+            let see_hidden = issuer.has_privilege(Privilege::SeeHidden);
+            if channel.secret && !in_channel && !see_hidden {
                 break;
             }
 
@@ -1177,7 +1493,7 @@ impl super::StateInner {
             for (member, modes) in &channel.members {
                 let target = &self.clients[*member];
                 if (args.filter.operator && !target.operator)
-                    || (!issuer.operator && target.invisible && !in_channel && *member != ctx.id)
+                    || (!see_hidden && target.invisible && !in_channel && *member != ctx.id)
                 {
                     // Either the target isn't an operator while the client filtered for
                     // operators, or the client cannot see the member.
@@ -1201,7 +1517,7 @@ impl super::StateInner {
         args: data::req::WhoMask<'_>,
     ) -> Result {
         let issuer = &self.clients[ctx.id];
-        if !issuer.operator {
+        if !issuer.has_privilege(Privilege::SeeHidden) {
             ctx.rb
                 .reply(rpl::ENDOFWHO)
                 .param(args.mask.get())
@@ -1268,7 +1584,9 @@ impl super::StateInner {
     // WHOIS
 
     pub fn cmd_whois(&self, ctx: CommandContext<'_>, nick: data::Nickname<'_>) -> Result {
-        let (_, target_client) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, nick)?;
+        let (target_id, target_client) =
+            find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, nick)?;
+        let issuer = &self.clients[ctx.id];
 
         ctx.rb.lr_batch_begin();
         ctx.rb
@@ -1297,6 +1615,25 @@ impl super::StateInner {
                 .trailing_param(away_msg);
         }
 
+        if target_client.tls() {
+            let msg = ctx.rb.reply(rpl::WHOISSECURE).param(target_client.nick());
+            match target_client.tls_info() {
+                Some(info) if target_id == ctx.id || issuer.has_privilege(Privilege::SeeHidden) => {
+                    msg.fmt_trailing_param(format_args!("{} ({})", lines::WHOIS_SECURE, info));
+                }
+                _ => msg.trailing_param(lines::WHOIS_SECURE),
+            }
+        }
+
+        if let Some(certfp) = target_client.certfp() {
+            if target_id == ctx.id || issuer.has_privilege(Privilege::SeeHidden) {
+                ctx.rb
+                    .reply(rpl::WHOISCERTFP)
+                    .param(target_client.nick())
+                    .fmt_trailing_param(format_args!("{} {}", lines::WHOIS_CERTFP, certfp));
+            }
+        }
+
         ctx.rb
             .reply(rpl::ENDOFWHOIS)
             .param(target_client.nick())
@@ -3,8 +3,105 @@
 //! <https://ircv3.net/irc/>
 
 use super::{CommandContext, HandlerResult as Result};
-use crate::{data, lines};
-use ellidri_tokens::{Buffer, Command};
+use crate::{data, lines, Client};
+use crate::data::cap::Version;
+use ellidri_tokens::{Buffer, Command, MESSAGE_LENGTH};
+
+/// Capabilities that depend on the configuration, with their value.
+pub type ConfigCaps = Vec<(&'static str, Option<String>)>;
+
+/// Returns the word that advertises `cap` in CAP replies, or `None` if it must not be advertised
+/// to the client.
+fn cap_word(cap: &str, value: Option<&str>, version: Version) -> Option<String> {
+    match value {
+        Some(value) if version == Version::V302 => Some(format!("{}={}", cap, value)),
+        // The STS policy is useless without its value.
+        _ if cap == data::cap::STS => None,
+        _ => Some(cap.to_owned()),
+    }
+}
+
+/// Splits a list of capabilities into lines of at most `max_len` bytes.
+fn cap_lines<'a>(caps: impl IntoIterator<Item = &'a str>, max_len: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for cap in caps {
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() && max_len < line.len() + 1 + cap.len() {
+            lines.push(String::from(cap));
+        } else {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(cap);
+        }
+    }
+    lines
+}
+
+/// Returns the maximum length of the list of capabilities in CAP replies sent to `client`.
+fn cap_lines_len(domain: &str, client: &Client) -> usize {
+    // ":<domain> CAP <nick> LS * :<caps>\r\n"
+    MESSAGE_LENGTH.saturating_sub(domain.len() + client.nick().len() + 16)
+}
+
+impl super::StateInner {
+    /// Returns the capabilities that are advertised depending on the configuration, for a client
+    /// that uses TLS or not.
+    pub fn config_caps(&self, tls: bool) -> ConfigCaps {
+        let mut caps = Vec::new();
+        if let Some(sts) = self.sts_value(tls) {
+            caps.push((data::cap::STS, Some(sts)));
+        }
+        caps
+    }
+
+    /// Sends CAP NEW and CAP DEL to clients that have cap-notify enabled, for the capabilities
+    /// that changed since `old` was returned by `config_caps(false)` and `config_caps(true)`.
+    pub fn notify_caps(&self, old: &[ConfigCaps; 2]) {
+        let new = [self.config_caps(false), self.config_caps(true)];
+        if old == &new {
+            return;
+        }
+
+        for (_, client) in &self.clients {
+            let version = client.cap_version;
+            if !client.cap_enabled.cap_notify && version != Version::V302 {
+                continue;
+            }
+            let (old, new) = (&old[client.tls() as usize], &new[client.tls() as usize]);
+            let max_len = cap_lines_len(&self.domain, client);
+
+            // Only the capabilities the client has been told about can be deleted.
+            let del: Vec<&str> = old
+                .iter()
+                .filter(|(cap, _)| new.iter().all(|(new_cap, _)| new_cap != cap))
+                .filter(|(cap, value)| cap_word(cap, value.as_deref(), version).is_some())
+                .map(|(cap, _)| *cap)
+                .collect();
+            let added: Vec<String> = new
+                .iter()
+                .filter(|cap| !old.contains(cap))
+                .filter_map(|(cap, value)| cap_word(cap, value.as_deref(), version))
+                .collect();
+
+            let added: Vec<&str> = added.iter().map(String::as_str).collect();
+
+            for (subcommand, caps) in [("DEL", &del), ("NEW", &added)].iter() {
+                if caps.is_empty() {
+                    continue;
+                }
+                for line in cap_lines(caps.iter().cloned(), max_len) {
+                    let mut msg = Buffer::new();
+                    msg.message(&self.domain, Command::Cap)
+                        .param(client.nick())
+                        .param(subcommand)
+                        .trailing_param(&line);
+                    client.send(msg);
+                }
+            }
+        }
+    }
+}
 
 /// Handler for the CAP command.
 ///
@@ -19,17 +116,37 @@ impl super::StateInner {
         Ok(())
     }
 
-    pub fn cmd_cap_ls(&mut self, ctx: CommandContext<'_>, version: data::cap::Version) -> Result {
+    pub fn cmd_cap_ls(&mut self, ctx: CommandContext<'_>, version: Version) -> Result {
+        let config_caps = self.config_caps(self.clients[ctx.id].tls());
         let client = &mut self.clients[ctx.id];
 
         if client.cap_version < version {
             client.cap_version = version;
         }
-
-        let mut msg = ctx.rb.reply(Command::Cap).param("LS");
-
-        let trailing = msg.raw_trailing_param();
-        trailing.push_str(data::cap::ls_common());
+        let version = client.cap_version;
+
+        let config_caps: Vec<String> = config_caps
+            .iter()
+            .filter_map(|(cap, value)| cap_word(cap, value.as_deref(), version))
+            .collect();
+        let caps = data::cap::ls_common()
+            .split(' ')
+            .chain(config_caps.iter().map(String::as_str));
+
+        // Only CAP 302 clients support replies on several lines.
+        let max_len = match version {
+            Version::V302 => cap_lines_len(&self.domain, client),
+            Version::V300 => usize::MAX,
+        };
+        let lines = cap_lines(caps, max_len);
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let mut msg = ctx.rb.reply(Command::Cap).param("LS");
+            if i < last {
+                msg = msg.param("*");
+            }
+            msg.trailing_param(line);
+        }
 
         Ok(())
     }
@@ -37,6 +154,13 @@ impl super::StateInner {
     pub fn cmd_cap_req(&mut self, ctx: CommandContext<'_>, req: data::cap::Diff) -> Result {
         let client = &mut self.clients[ctx.id];
 
+        if req.sts.is_some() {
+            // STS is a policy, it cannot be enabled.
+            let mut msg = ctx.rb.reply(Command::Cap).param("NAK");
+            req.write(msg.raw_trailing_param());
+            return Err(());
+        }
+
         client.cap_enabled.update(req);
 
         let mut msg = ctx.rb.reply(Command::Cap).param("ACK");
@@ -92,3 +216,16 @@ impl super::StateInner {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_lines() {
+        let caps = ["batch", "cap-notify", "sts=duration=60"];
+        assert_eq!(cap_lines(caps.iter().cloned(), 512), vec!["batch cap-notify sts=duration=60"]);
+        assert_eq!(cap_lines(caps.iter().cloned(), 16), vec!["batch cap-notify", "sts=duration=60"]);
+        assert_eq!(cap_lines(caps.iter().cloned(), 4), vec!["batch", "cap-notify", "sts=duration=60"]);
+    }
+} // mod tests
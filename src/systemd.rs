@@ -0,0 +1,60 @@
+//! systemd integration.
+//!
+//! When ellidri runs as a `Type=notify` service, it tells systemd when it is ready, when it is
+//! reloading its configuration and when it is stopping.  These functions do nothing when ellidri
+//! is not started by systemd.
+//!
+//! Listening sockets passed by systemd (socket activation) are taken by `control`, which matches
+//! them to bindings by address or by name.
+
+use std::env;
+
+#[cfg(unix)]
+use sd_notify::NotifyState;
+
+#[cfg(unix)]
+fn notify(states: &[NotifyState<'_>]) {
+    if let Err(err) = sd_notify::notify(states) {
+        log::warn!("Failed to notify systemd: {}", err);
+    }
+}
+
+/// Tells systemd that ellidri is ready to accept connections.
+pub fn ready() {
+    #[cfg(unix)]
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tells systemd that ellidri is reloading its configuration.  `ready` must be called once it
+/// is done.
+pub fn reloading() {
+    #[cfg(unix)]
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+/// Tells systemd that ellidri is shutting down.
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Tells systemd that the process `pid` replaces this one.
+pub fn main_pid(pid: u32) {
+    #[cfg(unix)]
+    notify(&[NotifyState::MainPid(pid)]);
+
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+/// Returns the names of the sockets passed by systemd, in order.
+///
+/// Must be called before the runtime is started, as it modifies environment variables.
+pub fn listen_fd_names() -> Vec<String> {
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_FDNAMES");
+    names.split(':').map(String::from).collect()
+}
@@ -0,0 +1,207 @@
+//! Connection throttling.
+//!
+//! `Throttle` keeps track of connection attempts per subnet over a sliding window, and of the
+//! number of simultaneous connections per IP address.  Bindings check it as soon as a connection
+//! is accepted, before doing any work such as a TLS handshake.
+
+use crate::config;
+use crate::util::Cidr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The reason a connection has been throttled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttled {
+    /// Too many connection attempts from the subnet.  `first` is true for the first refused
+    /// attempt in the window, so that it is only reported once.
+    TooManyAttempts { subnet: Cidr, first: bool },
+
+    /// Too many simultaneous connections from the IP address.  `first` is true for the first
+    /// refused connection until one of the connections of `ip` is closed.
+    TooManyConnections { ip: IpAddr, first: bool },
+}
+
+struct Attempts {
+    times: VecDeque<Instant>,
+    throttled: bool,
+}
+
+struct ThrottleInner {
+    config: config::Throttle,
+    attempts: HashMap<Cidr, Attempts>,
+    connections: HashMap<IpAddr, usize>,
+
+    /// Addresses that have been refused for having too many connections.
+    over_limit: HashSet<IpAddr>,
+
+    last_cleanup: Instant,
+}
+
+/// Shared throttling state.  This is an `Arc` to the real data, so it's cheap to clone.
+#[derive(Clone)]
+pub struct Throttle(Arc<Mutex<ThrottleInner>>);
+
+/// Keeps a connection counted until it is dropped.
+pub struct Guard {
+    throttle: Throttle,
+    ip: Option<IpAddr>,
+}
+
+impl Throttle {
+    pub fn new(config: config::Throttle) -> Self {
+        Self(Arc::new(Mutex::new(ThrottleInner {
+            config,
+            attempts: HashMap::new(),
+            connections: HashMap::new(),
+            over_limit: HashSet::new(),
+            last_cleanup: Instant::now(),
+        })))
+    }
+
+    /// Applies new settings.  Connection attempts and counts are kept.
+    pub fn rehash(&self, config: config::Throttle) {
+        self.0.lock().unwrap().config = config;
+    }
+
+    /// Registers a connection attempt from `ip`.
+    ///
+    /// Returns a guard that must be kept for the whole lifetime of the connection, or the reason
+    /// why the connection must be closed.
+    pub fn connect(&self, ip: IpAddr) -> Result<Guard, Throttled> {
+        let counted = self.0.lock().unwrap().connect(ip, Instant::now())?;
+        Ok(Guard {
+            throttle: self.clone(),
+            ip: if counted { Some(ip) } else { None },
+        })
+    }
+}
+
+impl ThrottleInner {
+    /// Returns whether the connection is counted, i.e. whether `ip` is not exempt.
+    fn connect(&mut self, ip: IpAddr, now: Instant) -> Result<bool, Throttled> {
+        if self.config.exempt.iter().any(|cidr| cidr.contains(ip)) {
+            return Ok(false);
+        }
+
+        let window = Duration::from_millis(self.config.window);
+        if window <= now.duration_since(self.last_cleanup) {
+            self.attempts.retain(|_, attempts| {
+                let last = attempts.times.back();
+                last.filter(|last| now.duration_since(**last) < window).is_some()
+            });
+            self.last_cleanup = now;
+        }
+
+        let connections = self.connections.get(&ip).cloned().unwrap_or(0);
+        if self.config.max_connections <= connections {
+            let first = self.over_limit.insert(ip);
+            return Err(Throttled::TooManyConnections { ip, first });
+        }
+
+        let prefix_len = match ip {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        let subnet = Cidr::of(ip, prefix_len);
+        let attempts = self.attempts.entry(subnet).or_insert_with(|| Attempts {
+            times: VecDeque::new(),
+            throttled: false,
+        });
+        while let Some(first) = attempts.times.front() {
+            if now.duration_since(*first) < window {
+                break;
+            }
+            attempts.times.pop_front();
+        }
+        if self.config.attempts <= attempts.times.len() {
+            let first = !attempts.throttled;
+            attempts.throttled = true;
+            return Err(Throttled::TooManyAttempts { subnet, first });
+        }
+        attempts.times.push_back(now);
+        attempts.throttled = false;
+
+        *self.connections.entry(ip).or_insert(0) += 1;
+        Ok(true)
+    }
+
+    fn disconnect(&mut self, ip: IpAddr) {
+        self.over_limit.remove(&ip);
+        if let Some(connections) = self.connections.get_mut(&ip) {
+            *connections -= 1;
+            if *connections == 0 {
+                self.connections.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            if let Ok(mut inner) = self.throttle.0.lock() {
+                inner.disconnect(ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let config = config::Throttle {
+            attempts: 2,
+            window: 1000,
+            max_connections: 3,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            exempt: vec!["10.0.0.1".parse().unwrap()],
+        };
+        let throttle = Throttle::new(config);
+        let mut inner = throttle.0.lock().unwrap();
+        let start = Instant::now();
+        let ip1: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip2: IpAddr = "192.0.2.2".parse().unwrap();
+        let subnet = Cidr::of(ip1, 24);
+
+        assert_eq!(inner.connect(ip1, start), Ok(true));
+        assert_eq!(inner.connect(ip2, start), Ok(true));
+        assert_eq!(
+            inner.connect(ip1, start),
+            Err(Throttled::TooManyAttempts { subnet, first: true })
+        );
+        assert_eq!(
+            inner.connect(ip2, start),
+            Err(Throttled::TooManyAttempts { subnet, first: false })
+        );
+
+        let later = start + Duration::from_millis(1000);
+        assert_eq!(inner.connect(ip1, later), Ok(true));
+        assert_eq!(inner.connect(ip1, later), Ok(true));
+
+        let later = later + Duration::from_millis(1000);
+        assert_eq!(
+            inner.connect(ip1, later),
+            Err(Throttled::TooManyConnections { ip: ip1, first: true })
+        );
+        assert_eq!(
+            inner.connect(ip1, later),
+            Err(Throttled::TooManyConnections { ip: ip1, first: false })
+        );
+        inner.disconnect(ip1);
+        assert_eq!(inner.connect(ip1, later), Ok(true));
+        assert_eq!(
+            inner.connect(ip1, later),
+            Err(Throttled::TooManyConnections { ip: ip1, first: true })
+        );
+
+        for _ in 0..10 {
+            assert_eq!(inner.connect("10.0.0.1".parse().unwrap(), later), Ok(false));
+        }
+    }
+} // mod tests
@@ -1,7 +1,12 @@
 use rand_chacha::rand_core::{RngCore, SeedableRng};
 use rand_chacha::ChaChaRng;
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::time;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{fmt, time};
 
 thread_local! {
     static RNG: RefCell<ChaChaRng> = RefCell::new(ChaChaRng::seed_from_u64(time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs()));
@@ -59,6 +64,111 @@ impl MaskSet {
     }
 }
 
+/// A range of IP addresses, written in CIDR notation (e.g. `192.168.0.0/16` or `fd00::/8`).
+///
+/// An address without prefix length is a range of one address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns the range of the given length that contains `ip`.
+    ///
+    /// `prefix_len` is truncated to the length of the address.
+    pub fn of(ip: IpAddr, prefix_len: u8) -> Self {
+        let addr = match ip {
+            IpAddr::V4(ip) => {
+                let prefix_len = prefix_len.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let prefix_len = prefix_len.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        };
+        let prefix_len = if addr.is_ipv4() { prefix_len.min(32) } else { prefix_len.min(128) };
+        Self { addr, prefix_len }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_match(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_match(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V4(ip)) => {
+                prefix_match(&net.octets(), &ip.to_ipv6_mapped().octets(), self.prefix_len)
+            }
+            (IpAddr::V4(net), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => prefix_match(&net.octets(), &ip.octets(), self.prefix_len),
+                None => false,
+            },
+        }
+    }
+}
+
+fn prefix_match(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.splitn(2, '/');
+        let addr: IpAddr = split
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| "invalid IP address")?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match split.next() {
+            Some(len) => len.parse().map_err(|_| "invalid prefix length")?,
+            None => max_len,
+        };
+        if max_len < prefix_len {
+            return Err("prefix length is too large");
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = &'static str;
+
+    fn try_from(val: String) -> Result<Self, Self::Error> {
+        val.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(val: Cidr) -> Self {
+        val.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
 // Taken from <https://golang.org/src/path/match.go?s=1084:1142#L28>
 pub fn match_mask(mut mask: &str, mut s: &str) -> bool {
     'pattern: while !mask.is_empty() {
@@ -132,6 +242,46 @@ pub fn new_message_id() -> String {
     std::str::from_utf8(&encoded).unwrap().to_owned()
 }
 
+/// Returns a random key suitable for `cloak`.
+pub fn new_cloak_key() -> String {
+    use ring::rand::SecureRandom as _;
+
+    let mut bytes = [0x0; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("failed to generate a cloak key");
+    base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+}
+
+/// Returns the cloaked host of the given IP address.
+///
+/// The cloak is derived from an HMAC of the address, so that it cannot be reversed without `key`.
+pub fn cloak(key: &str, ip: IpAddr) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let tag = hmac::sign(&key, ip.to_string().as_bytes());
+    let tag = tag.as_ref();
+
+    let mut res = String::with_capacity(24);
+    for (i, byte) in tag[..12].iter().enumerate() {
+        if i != 0 && i % 4 == 0 {
+            res.push('.');
+        }
+        let _ = write!(res, "{:02x}", byte);
+    }
+    res.push_str(".ip");
+    res
+}
+
+/// Returns the SHA-256 fingerprint of the given certificate, in lowercase hexadecimal.
+pub fn fingerprint(der: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, der);
+    let mut res = String::with_capacity(64);
+    for byte in hash.as_ref() {
+        let _ = write!(res, "{:02x}", byte);
+    }
+    res
+}
+
 pub fn time_precise() -> String {
     chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
 }
@@ -180,4 +330,39 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cases = [
+            ("127.0.0.1", "127.0.0.1", true),
+            ("127.0.0.1", "127.0.0.2", false),
+            ("10.0.0.0/8", "10.42.1.3", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("192.168.0.0/23", "192.168.1.255", true),
+            ("192.168.0.0/23", "192.168.2.0", false),
+            ("0.0.0.0/0", "203.0.113.7", true),
+            ("fd00::/8", "fd12:3456::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("10.0.0.0/8", "::ffff:10.1.2.3", true),
+            ("::ffff:0:0/96", "10.1.2.3", true),
+        ];
+
+        for (cidr, ip, contained) in &cases {
+            let parsed: Cidr = cidr.parse().unwrap();
+            assert_eq!(
+                parsed.contains(ip.parse().unwrap()),
+                *contained,
+                "{:?}.contains({:?})",
+                cidr,
+                ip
+            );
+        }
+
+        let subnet = Cidr::of("192.168.42.7".parse().unwrap(), 16);
+        assert_eq!(subnet, "192.168.0.0/16".parse().unwrap());
+        assert_eq!(Cidr::of("fd12::1".parse().unwrap(), 200).to_string(), "fd12::1/128");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not an ip/8".parse::<Cidr>().is_err());
+    }
 } // mod tests